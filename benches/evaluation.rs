@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use better_dev_calc::{evaluate_rpn, evaluate_streaming, shunting_yard, tokenize, Settings};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+// Expressions shaped like the ones that stress each stage: many tokens in a row, deep nesting, and a
+// mix of every precedence level so the operator stack keeps growing and shrinking.
+fn inputs() -> Vec<(&'static str, String)>
+{
+    let flat = vec!["d1"; 1000].join(" + ");
+    let nested = format!("{}d1{}", "(d1 + ".repeat(200), ")".repeat(200));
+    let mixed = vec!["hFF * d3 - b101 << d2 | o17 & (d100 / d7 % d5) ^ d9"; 50].join(" + ");
+    let grouped = vec!["h DEAD BEEF"; 200].join(" ^ ");
+    vec![("flat", flat), ("nested", nested), ("mixed", mixed), ("grouped", grouped)]
+}
+
+fn bench_tokenize(c: &mut Criterion)
+{
+    let mut group = c.benchmark_group("tokenize");
+    for (name, input) in inputs()
+    {
+        group.bench_function(name, |b| b.iter(|| tokenize(&input).unwrap()));
+    }
+    group.finish();
+}
+
+fn bench_shunting_yard(c: &mut Criterion)
+{
+    let mut group = c.benchmark_group("shunting_yard");
+    for (name, input) in inputs()
+    {
+        let tokens = tokenize(&input).unwrap();
+        group.bench_function(name, |b| b.iter_batched(|| tokens.clone(), |tokens| shunting_yard(tokens).unwrap(), BatchSize::SmallInput));
+    }
+    group.finish();
+}
+
+fn bench_evaluate_rpn(c: &mut Criterion)
+{
+    let variables = HashMap::new();
+    let settings = Settings::default();
+    let mut group = c.benchmark_group("evaluate_rpn");
+    for (name, input) in inputs()
+    {
+        let rpn = shunting_yard(tokenize(&input).unwrap()).unwrap();
+        group.bench_function(name, |b| b.iter_batched(|| rpn.clone(), |rpn| evaluate_rpn(rpn, &variables, &settings).unwrap(), BatchSize::SmallInput));
+    }
+    group.finish();
+}
+
+// The whole pipeline on a million operators, staged (three passes, each buffering every token) against the
+// single streaming pass.
+fn bench_pipeline(c: &mut Criterion)
+{
+    let variables = HashMap::new();
+    let settings = Settings::default();
+    let input = vec!["d1"; 1_000_001].join(" + ");
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10).measurement_time(Duration::from_secs(10));
+    group.bench_function("staged", |b| b.iter(|| evaluate_rpn(shunting_yard(tokenize(&input).unwrap()).unwrap(), &variables, &settings).unwrap()));
+    group.bench_function("streaming", |b| b.iter(|| evaluate_streaming(&input, &variables, &settings).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_tokenize, bench_shunting_yard, bench_evaluate_rpn, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,155 @@
+use std::fmt;
+
+use crate::number::Base;
+
+// Everything that can go wrong while evaluating an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalcError
+{
+    EmptyExpression,
+    EmptyNumber,
+    InvalidBase(char),
+    MissingDigits(Base),
+    InvalidDigit(Base, char, String),
+    InvalidNumber(Base, String),
+    NumberTooLarge(String),
+    UnsignedTooLarge(String),
+    InvalidCharacter(char),
+    InvalidOperator(char),
+    InvalidShiftAmount(i64),
+    DivisionByZero,
+    EmptyParentheses,
+    UnmatchedOpenParen,
+    UnmatchedCloseParen,
+    InvalidExpression,
+    UndefinedVariable(String),
+    InvalidVariableName(String),
+    // A name that is only a base prefix letter, like `h`
+    BasePrefixName(Base),
+    SelfReferentialVariable(String),
+    UnknownFunction(String),
+    WrongArgumentCount(String, usize, usize),
+    MultipleValues(String),
+    InputTooLong(usize),
+    InvalidArgument(String, i64),
+    Overflow(String),
+    AmbiguousOutputBase,
+    // The word after a trailing `in` or `as` that doesn't name a base
+    UnknownBaseName(String),
+    Timeout,
+    InvalidDefinition,
+    BuiltinFunction(String),
+    RecursionLimit(String),
+    MissingOperand(&'static str),
+    MismatchedBracket(char, char),
+    UnmatchedConditional,
+    UnexpectedColon,
+    NoLastResult,
+    DisabledOperator(&'static str),
+    AssertionFailed(String, String),
+}
+
+impl CalcError
+{
+    // A stable name for the kind of error, for scripts to branch on instead of matching the message.
+    // Messages may be reworded; these are only ever added to.
+    pub fn code(&self) -> &'static str
+    {
+        match self
+        {
+            CalcError::EmptyExpression => "EMPTY_EXPRESSION",
+            CalcError::EmptyNumber => "EMPTY_NUMBER",
+            CalcError::InvalidBase(_) => "INVALID_BASE",
+            CalcError::MissingDigits(_) => "MISSING_DIGITS",
+            CalcError::InvalidDigit(..) => "INVALID_DIGIT",
+            CalcError::InvalidNumber(..) => "INVALID_NUMBER",
+            CalcError::NumberTooLarge(_) | CalcError::UnsignedTooLarge(_) => "NUMBER_TOO_LARGE",
+            CalcError::InvalidCharacter(_) => "INVALID_CHARACTER",
+            CalcError::InvalidOperator(_) => "INVALID_OPERATOR",
+            CalcError::InvalidShiftAmount(_) => "INVALID_SHIFT",
+            CalcError::DivisionByZero => "DIV_BY_ZERO",
+            CalcError::EmptyParentheses => "EMPTY_PARENS",
+            CalcError::UnmatchedOpenParen => "UNMATCHED_PAREN",
+            CalcError::UnmatchedCloseParen => "UNMATCHED_CLOSE_PAREN",
+            CalcError::InvalidExpression => "INVALID_EXPRESSION",
+            CalcError::UndefinedVariable(_) => "UNDEFINED_VARIABLE",
+            CalcError::InvalidVariableName(_) => "INVALID_VARIABLE_NAME",
+            CalcError::BasePrefixName(_) => "BASE_PREFIX_NAME",
+            CalcError::SelfReferentialVariable(_) => "SELF_REFERENCE",
+            CalcError::UnknownFunction(_) => "UNKNOWN_FUNCTION",
+            CalcError::WrongArgumentCount(..) => "WRONG_ARGUMENT_COUNT",
+            CalcError::MultipleValues(_) => "MULTIPLE_VALUES",
+            CalcError::InputTooLong(_) => "INPUT_TOO_LONG",
+            CalcError::InvalidArgument(..) => "INVALID_ARGUMENT",
+            CalcError::Overflow(_) => "OVERFLOW",
+            CalcError::AmbiguousOutputBase => "AMBIGUOUS_OUTPUT_BASE",
+            CalcError::UnknownBaseName(_) => "UNKNOWN_BASE_NAME",
+            CalcError::Timeout => "TIMEOUT",
+            CalcError::InvalidDefinition => "INVALID_DEFINITION",
+            CalcError::BuiltinFunction(_) => "BUILTIN_FUNCTION",
+            CalcError::RecursionLimit(_) => "RECURSION_LIMIT",
+            CalcError::MissingOperand(_) => "MISSING_OPERAND",
+            CalcError::MismatchedBracket(..) => "MISMATCHED_BRACKET",
+            CalcError::UnmatchedConditional => "UNMATCHED_CONDITIONAL",
+            CalcError::UnexpectedColon => "UNEXPECTED_COLON",
+            CalcError::NoLastResult => "NO_LAST_RESULT",
+            CalcError::DisabledOperator(_) => "OPERATOR_DISABLED",
+            CalcError::AssertionFailed(..) => "ASSERTION_FAILED",
+        }
+    }
+}
+
+impl fmt::Display for CalcError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            CalcError::EmptyExpression => write!(f, "Empty expression"),
+            CalcError::EmptyNumber => write!(f, "Empty number"),
+            CalcError::InvalidBase(c) => write!(f, "Invalid base: {}", c),
+            CalcError::MissingDigits(base) => write!(f, "No digits after the {} prefix '{}'", base.name(), base.prefix()),
+            CalcError::InvalidDigit(base, digit, digits) => write!(f, "'{}' is not a {} digit: {}", digit, base.name(), digits),
+            CalcError::InvalidNumber(base, digits) => write!(f, "Invalid {} number: {}", base.name(), digits),
+            CalcError::NumberTooLarge(digits) => write!(f, "Number too large for i64: {}", digits),
+            CalcError::UnsignedTooLarge(digits) => write!(f, "Number too large for u64: {}", digits),
+            CalcError::InvalidCharacter(c) => write!(f, "Invalid character: {}", c),
+            CalcError::InvalidOperator(c) => write!(f, "Invalid operator: {}", c),
+            CalcError::InvalidShiftAmount(amount) => write!(f, "Invalid shift amount: {}", amount),
+            CalcError::DivisionByZero => write!(f, "Division by zero"),
+            CalcError::EmptyParentheses => write!(f, "Empty parentheses"),
+            CalcError::UnmatchedOpenParen => write!(f, "Unmatched open bracket"),
+            CalcError::UnmatchedCloseParen => write!(f, "Unmatched close bracket"),
+            CalcError::InvalidExpression => write!(f, "Invalid expression"),
+            CalcError::UndefinedVariable(name) => write!(f, "Undefined variable: {}", name),
+            CalcError::InvalidVariableName(name) => write!(f, "Invalid variable name: {}", name),
+            CalcError::BasePrefixName(base) => write!(f, "Invalid name: {} is the {} base prefix", base.prefix(), base.name()),
+            CalcError::SelfReferentialVariable(name) => write!(f, "Variable {} is defined in terms of itself", name),
+            CalcError::UnknownFunction(name) => write!(f, "Unknown function: {}", name),
+            CalcError::WrongArgumentCount(name, expected, found) => 
+            {
+                let noun = if *expected == 1 { "argument" } else { "arguments" };
+                write!(f, "{} takes {} {}, got {}", name, expected, noun, found)
+            }
+            CalcError::MultipleValues(name) => write!(f, "{} gives several values and can only be used on its own", name),
+            CalcError::InputTooLong(limit) => write!(f, "Input too long (limit {} bytes)", limit),
+            CalcError::InvalidArgument(name, value) => write!(f, "Invalid argument for {}: {}", name, value),
+            CalcError::Overflow(operation) => write!(f, "Overflow in {}", operation),
+            CalcError::AmbiguousOutputBase => write!(f, "Ambiguous output base"),
+            CalcError::UnknownBaseName(name) => write!(f, "Unknown base: {} (use d, dec, decimal, h, hex, hexadecimal, b, bin, binary, o, oct or octal)", name),
+            CalcError::Timeout => write!(f, "Evaluation timed out"),
+            CalcError::InvalidDefinition => write!(f, "Invalid function definition (use def name(x, y) = expression)"),
+            CalcError::BuiltinFunction(name) => write!(f, "{} is a built-in function", name),
+            CalcError::RecursionLimit(name) => write!(f, "Too many nested calls in {}", name),
+            CalcError::MissingOperand(symbol) => write!(f, "Missing operand for '{}'", symbol),
+            CalcError::MismatchedBracket(open, close) => write!(f, "Mismatched brackets: '{}' closed by '{}'", open, close),
+            CalcError::UnmatchedConditional => write!(f, "'?' without a matching ':'"),
+            CalcError::UnexpectedColon => write!(f, "':' without a '?' before it"),
+            CalcError::NoLastResult => write!(f, "No result yet for '_'"),
+            CalcError::DisabledOperator(symbol) => write!(f, "Operator '{}' is disabled", symbol),
+            CalcError::AssertionFailed(actual, expected) => write!(f, "Assertion failed: {} != {}", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for CalcError {}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::error::CalcError;
+use crate::expr::{eval_ast, overflowing_neg, Expr};
+use crate::number::Number;
+use crate::settings::Settings;
+use crate::token::{scan_into, Operation, Token};
+
+// Converts infix tokens to Reverse Polish Notation using the shunting yard algorithm.
+pub fn shunting_yard(tokens: Vec<Token>) -> Result<Vec<Token>, CalcError> 
+{
+    let mut output = Vec::new();
+    let mut yard = Yard::new(|token| 
+    {
+        output.push(token);
+        Ok(())
+    });
+    for token in tokens 
+    {
+        yard.push(token)?;
+    }
+    yard.finish()?;
+    Ok(output)
+}
+
+// The shunting yard one token at a time, writing the RPN to `emit` as it goes, so that whatever takes it
+// doesn't have to wait for the whole expression.
+struct Yard<F> 
+{
+    emit: F,
+    // How many tokens have been written out
+    written: usize,
+    operator_stack: Vec<Token>,
+    // Output length when each open paren (or comma) was pushed, to spot groups that produced nothing
+    group_starts: Vec<usize>,
+    // Commas seen so far inside each open paren
+    comma_counts: Vec<usize>,
+}
+
+impl<F: FnMut(Token) -> Result<(), CalcError>> Yard<F> 
+{
+    fn new(emit: F) -> Self 
+    {
+        Yard { emit, written: 0, operator_stack: Vec::new(), group_starts: Vec::new(), comma_counts: Vec::new() }
+    }
+
+    fn output(&mut self, token: Token) -> Result<(), CalcError> 
+    {
+        self.written += 1;
+        (self.emit)(token)
+    }
+
+    // Writes out operators until an open paren (left in place) or one that binds looser than `precedence`.
+    // A conditional on the stack is a `?` still waiting for its `:`, or a `:` whose `else` part is being read.
+    fn unwind(&mut self, precedence: u8) -> Result<(), CalcError> 
+    {
+        let conditional = Operation::Conditional.precedence();
+        loop 
+        {
+            match self.operator_stack.last() 
+            {
+                Some(Token::Operation(Operation::OpenParen)) => break,
+                Some(Token::Operation(Operation::Conditional)) if precedence <= conditional => return Err(CalcError::UnmatchedConditional),
+                Some(Token::Operation(op)) if op.precedence() >= precedence => 
+                {
+                    let op = self.operator_stack.pop().unwrap();
+                    self.output(op)?;
+                }
+                Some(Token::Colon) if precedence <= conditional => 
+                {
+                    self.operator_stack.pop();
+                    self.output(Token::Operation(Operation::Conditional))?;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, token: Token) -> Result<(), CalcError> 
+    {
+        match token 
+        {
+            Token::Number(_) | Token::Variable(_) | Token::Call(..) | Token::UserCall(..) => self.output(token)?,
+            Token::Function(_) | Token::UserFunction(_) => self.operator_stack.push(token),
+            Token::Operation(Operation::OpenParen) => 
+            {
+                self.group_starts.push(self.written);
+                self.comma_counts.push(0);
+                self.operator_stack.push(token);
+            }
+            Token::Comma => 
+            {
+                self.unwind(0)?;
+                match (self.group_starts.last_mut(), self.comma_counts.last_mut()) 
+                {
+                    (Some(start), Some(commas)) if *start != self.written => 
+                    {
+                        *start = self.written;
+                        *commas += 1;
+                    }
+                    _ => return Err(CalcError::InvalidExpression),
+                }
+            }
+            Token::Operation(Operation::CloseParen) => 
+            {
+                self.unwind(0)?;
+                if self.operator_stack.pop() != Some(Token::Operation(Operation::OpenParen)) 
+                {
+                    return Err(CalcError::UnmatchedCloseParen);
+                }
+                let commas = self.comma_counts.pop().unwrap_or(0);
+                if self.group_starts.pop() == Some(self.written) 
+                {
+                    return Err(if commas > 0 { CalcError::InvalidExpression } else { CalcError::EmptyParentheses });
+                }
+
+                if let Some(&Token::Function(function)) = self.operator_stack.last() 
+                {
+                    let count = commas + 1;
+                    if let Some(arity) = function.arity().filter(|arity| *arity != count) 
+                    {
+                        return Err(CalcError::WrongArgumentCount(function.name().to_string(), arity, count));
+                    }
+                    self.operator_stack.pop();
+                    self.output(Token::Call(function, count))?;
+                }
+                else if let Some(Token::UserFunction(name)) = self.operator_stack.last_mut() 
+                {
+                    // How many a user function takes is only known to the session that defined it
+                    let name = std::mem::take(name);
+                    self.operator_stack.pop();
+                    self.output(Token::UserCall(name, commas + 1))?;
+                }
+                else if commas > 0 
+                {
+                    // `(d1, d2)` without a function in front
+                    return Err(CalcError::InvalidExpression);
+                }
+            }
+            // A prefix operator has nothing to its left to finish first
+            Token::Operation(Operation::Negate) => self.operator_stack.push(token),
+            // Right-associative, so `a ? b : c ? d : e` leaves the first conditional open for the second
+            Token::Operation(Operation::Conditional) => 
+            {
+                self.unwind(Operation::Conditional.precedence() + 1)?;
+                self.operator_stack.push(token);
+            }
+            Token::Colon => 
+            {
+                self.unwind(Operation::Conditional.precedence() + 1)?;
+                // Conditionals nested in the `then` part are complete once their `:` is followed by this one
+                while self.operator_stack.last() == Some(&Token::Colon) 
+                {
+                    self.operator_stack.pop();
+                    self.output(Token::Operation(Operation::Conditional))?;
+                }
+                match self.operator_stack.last_mut() 
+                {
+                    Some(top @ Token::Operation(Operation::Conditional)) => *top = Token::Colon,
+                    _ => return Err(CalcError::UnexpectedColon),
+                }
+            }
+            Token::Operation(op) => 
+            {
+                self.unwind(op.precedence())?;
+                self.operator_stack.push(Token::Operation(op));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), CalcError> 
+    {
+        while let Some(op) = self.operator_stack.pop() 
+        {
+            match op 
+            {
+                Token::Operation(Operation::OpenParen) => 
+                {
+                    return Err(CalcError::UnmatchedOpenParen);
+                }
+                // Only possible when the paren after it was never closed, which is caught above
+                Token::Function(_) | Token::UserFunction(_) => return Err(CalcError::InvalidExpression),
+                Token::Operation(Operation::Conditional) => return Err(CalcError::UnmatchedConditional),
+                Token::Colon => self.output(Token::Operation(Operation::Conditional))?,
+                _ => self.output(op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Evaluates the expression in Reverse Polish Notation.
+pub fn evaluate_rpn(tokens: Vec<Token>, variables: &HashMap<String, Number>, settings: &Settings) -> Result<i64, CalcError> 
+{
+    match tokens.last() 
+    {
+        Some(Token::Call(function, _)) if function.labels().len() > 1 => Err(CalcError::MultipleValues(function.name().to_string())),
+        _ => Ok(evaluate_rpn_values(tokens, variables, settings)?.values[0]),
+    }
+}
+
+// What `evaluate_rpn_values` produced, and how many operators and functions it applied to get there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation
+{
+    pub values: Vec<i64>,
+    pub operations: usize,
+}
+
+// Like `evaluate_rpn`, but the outermost operation may be a function giving several values, such as `divmod`.
+pub fn evaluate_rpn_values(tokens: Vec<Token>, variables: &HashMap<String, Number>, settings: &Settings) -> Result<Evaluation, CalcError> 
+{
+    evaluate_rpn_with(tokens, variables, settings, &|name, _| Err(CalcError::UnknownFunction(name.to_string())))
+}
+
+// Works out a user function's value from its name and arguments.
+pub type FunctionCaller<'a> = dyn Fn(&str, &[i64]) -> Result<i64, CalcError> + 'a;
+
+// Like `evaluate_rpn_values`, with `call` working out each user function.
+pub fn evaluate_rpn_with(tokens: Vec<Token>, variables: &HashMap<String, Number>, settings: &Settings, call: &FunctionCaller) -> Result<Evaluation, CalcError> 
+{
+    eval_ast(&Expr::from_rpn(tokens)?, variables, settings, call)
+}
+
+// Tokenizes, converts and evaluates in a single pass, keeping only the operator and value stacks instead of every
+// token, for very long expressions. Numbers are read as `tokenize_with` reads them. The result is the same as
+// `evaluate_rpn` on the staged pipeline, but work happens left to right, so when an expression has several
+// errors the one reported can differ: `d1 / d0 + $` is a division by zero here and a bad character there.
+pub fn evaluate_streaming(expr: &str, variables: &HashMap<String, Number>, settings: &Settings) -> Result<i64, CalcError> 
+{
+    // The branch a conditional doesn't take mustn't be evaluated, which needs the tree
+    if expr.contains('?') 
+    {
+        let tokens = crate::token::tokenize_with(expr, settings)?;
+        return evaluate_rpn(shunting_yard(tokens)?, variables, settings);
+    }
+    let deadline = settings.timeout.map(|timeout| Instant::now() + timeout);
+    let mut tokens = 0usize;
+    let mut values: Vec<i64> = Vec::new();
+
+    let mut evaluate = |token: Token| -> Result<(), CalcError> 
+    {
+        // Reading the clock on every token would cost more than most operations
+        if tokens.is_multiple_of(256) && deadline.is_some_and(|deadline| Instant::now() > deadline) 
+        {
+            return Err(CalcError::Timeout);
+        }
+        tokens += 1;
+
+        let value = match token 
+        {
+            Token::Number(num) => num.value,
+            Token::Variable(name) => variables.get(&name).map(|num| num.value).ok_or(CalcError::UndefinedVariable(name))?,
+            Token::Operation(Operation::Negate) => 
+            {
+                let operand = values.pop().ok_or(CalcError::MissingOperand(Operation::Negate.symbol()))?;
+                overflowing_neg(operand, settings)?
+            }
+            // Expressions with a conditional take the staged path above
+            Token::Operation(Operation::Conditional) => return Err(CalcError::InvalidExpression),
+            Token::Operation(op) => 
+            {
+                let right = values.pop().ok_or(CalcError::MissingOperand(op.symbol()))?;
+                let left = values.pop().ok_or(CalcError::MissingOperand(op.symbol()))?;
+                op.apply(left, right, settings)?
+            }
+            Token::Call(function, count) => 
+            {
+                let start = values.len().checked_sub(count).ok_or(CalcError::InvalidExpression)?;
+                let args = values.split_off(start);
+                match function.apply(&args, settings)?[..] 
+                {
+                    [value] => value,
+                    _ => return Err(CalcError::MultipleValues(function.name().to_string())),
+                }
+            }
+            Token::UserCall(name, _) => return Err(CalcError::UnknownFunction(name)),
+            // Only calls are left once the shunting yard has run
+            Token::Function(_) | Token::UserFunction(_) | Token::Comma | Token::Colon => return Err(CalcError::InvalidExpression),
+        };
+        values.push(value);
+        Ok(())
+    };
+
+    let mut yard = Yard::new(&mut evaluate);
+    if let Some((_, err)) = scan_into(expr, settings, false, &mut |token, _| yard.push(token)).into_iter().next() 
+    {
+        return Err(err);
+    }
+    yard.finish()?;
+
+    match values[..] 
+    {
+        [value] => Ok(value),
+        _ => Err(CalcError::InvalidExpression),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::number::Base;
+    use crate::settings::OverflowMode;
+    use crate::token::tokenize;
+
+    fn evaluate(expr: &str) -> Result<i64, CalcError>
+    {
+        evaluate_rpn(shunting_yard(tokenize(expr)?)?, &HashMap::new(), &Settings::default())
+    }
+
+    #[test]
+    fn a_missing_operand_names_its_operator()
+    {
+        assert_eq!(evaluate("* d5"), Err(CalcError::MissingOperand("*")));
+        assert_eq!(evaluate("d5 +"), Err(CalcError::MissingOperand("+")));
+        assert_eq!(evaluate("d5 << "), Err(CalcError::MissingOperand("<<")));
+        assert_eq!(evaluate("-"), Err(CalcError::MissingOperand("-")));
+
+        // With an output base, as typed at the console
+        let mut session = crate::session::Session::new();
+        assert_eq!(session.eval("* d5 d").map(|result| result.to_string()), Err(CalcError::MissingOperand("*")));
+        assert_eq!(session.eval("d5 + d").map(|result| result.to_string()), Err(CalcError::MissingOperand("+")));
+    }
+
+    #[test]
+    fn brackets_match_their_own_kind()
+    {
+        assert_eq!(evaluate("(d1 + d2) * [d3 - {d1}]"), Ok(6));
+        assert_eq!(evaluate("{[(d2)]}"), Ok(2));
+        assert_eq!(evaluate("width(hFF)"), Ok(8));
+        assert_eq!(evaluate("(d5]"), Err(CalcError::MismatchedBracket('(', ']')));
+        assert_eq!(evaluate("[d5 + {d1)]"), Err(CalcError::MismatchedBracket('{', ')')));
+        assert_eq!(evaluate("(d5"), Err(CalcError::UnmatchedOpenParen));
+        assert_eq!(evaluate("[d5"), Err(CalcError::UnmatchedOpenParen));
+        assert_eq!(evaluate("d1)"), Err(CalcError::UnmatchedCloseParen));
+        assert_eq!(evaluate("d1]"), Err(CalcError::UnmatchedCloseParen));
+        assert_eq!(evaluate("(d1)) + d2"), Err(CalcError::UnmatchedCloseParen));
+        assert_eq!(evaluate("width(hFF)}"), Err(CalcError::UnmatchedCloseParen));
+
+        let mut session = crate::session::Session::new();
+        assert_eq!(session.eval("d1] d").map(|result| result.to_string()), Err(CalcError::UnmatchedCloseParen));
+        assert_eq!(session.eval("d1) d").map(|result| result.to_string()), Err(CalcError::UnmatchedCloseParen));
+        assert_eq!(CalcError::UnmatchedCloseParen.code(), "UNMATCHED_CLOSE_PAREN");
+        assert_eq!(CalcError::UnmatchedOpenParen.to_string(), "Unmatched open bracket");
+        assert_eq!(CalcError::UnmatchedCloseParen.to_string(), "Unmatched close bracket");
+    }
+
+    #[test]
+    fn conditional_takes_one_branch()
+    {
+        assert_eq!(evaluate("d5 > d3 ? d100 : d0"), Ok(100));
+        assert_eq!(evaluate("d5 < d3 ? d100 : d0"), Ok(0));
+        assert_eq!(evaluate("d1 ? d2 : d3 ? d4 : d5"), Ok(2));
+        assert_eq!(evaluate("d0 ? d2 : d0 ? d4 : d5"), Ok(5));
+        assert_eq!(evaluate("d2 + d3 ? d1 + d1 : d9"), Ok(2));
+
+        // The branch not taken is never worked out, so its errors don't fire
+        assert_eq!(evaluate("d0 ? d1 / d0 : d7"), Ok(7));
+        assert_eq!(evaluate("d1 ? d7 : d1 / d0"), Ok(7));
+        assert_eq!(evaluate(&format!("d1 ? d7 : d{} + d1", i64::MAX)), Ok(7));
+        assert_eq!(evaluate("d1 ? d1 / d0 : d7"), Err(CalcError::DivisionByZero));
+
+        assert_eq!(evaluate("d1 ? d2"), Err(CalcError::UnmatchedConditional));
+        assert_eq!(evaluate("d1 : d2"), Err(CalcError::UnexpectedColon));
+
+        let mut session = crate::session::Session::new();
+        assert_eq!(session.eval("d5 > d3 ? d100 : d0 d").map(|result| result.to_string()), Ok("d100".into()));
+        assert_eq!(session.eval("d0 ? d1 / d0 : d7 d").map(|result| result.to_string()), Ok("d7".into()));
+    }
+
+    #[test]
+    fn streaming_agrees_with_the_staged_pipeline()
+    {
+        let variables = HashMap::from([("x".to_string(), Number { value: 0xFF, base: Base::Hexadecimal })]);
+        let expressions = [
+            "d1 + d2 * d3", "(d1 + d2) * d3", "[d2 + d3] * {d4 - d1}", "d10 - d4 - d3", "d1 << d4 >> d2",
+            "-d2 * d3", "d10 * (-d2)", "-(-x)", "x & hF0 | b1 ^ h3", "d7 % d3 + d7 / d2", "d3 < d5", "d5 > d3",
+            "width(x) + d1", "sum(d1, d2, d3) * d2", "max(x, d10, width(d-1))", "h DEAD BEEF + d1", "d1_000 * d1_000",
+            "d2 ? d3 : d4", "d0 ? d1 / d0 : d7", "d9223372036854775807 + d1", "d-9223372036854775808 * d-1",
+            "-(d-9223372036854775808)", "d5",
+            // One error each, so both must find the same one
+            "d1 / d0", "d1 % d0", "d1 + y", "d1 +", "* d2", "(d1 + d2", "d1 + d2)", "(d1]", "d1 $ d2", "h", "hG",
+            "d1 << d64", "width()", "width(d1, d2)", "nosuch(d1)", "d1 d2", "",
+        ];
+        let modes = [Settings::default(), Settings { overflow: OverflowMode::Wrapping, ..Settings::default() }, Settings { unsigned: true, ..Settings::default() }];
+        for settings in &modes
+        {
+            for expr in expressions
+            {
+                let staged = crate::token::tokenize_with(expr, settings).and_then(shunting_yard).and_then(|rpn| evaluate_rpn(rpn, &variables, settings));
+                assert_eq!(evaluate_streaming(expr, &variables, settings), staged, "{}", expr);
+            }
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::error::CalcError;
+use crate::eval::{Evaluation, FunctionCaller};
+use crate::function::Function;
+use crate::number::{format_value, Base, Number};
+use crate::settings::{OverflowMode, Settings};
+use crate::token::{Operation, Token};
+
+// An expression as a tree, built from the RPN `shunting_yard` writes out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr
+{
+    Num(Number),
+    Var(String),
+    BinOp(Operation, Box<Expr>, Box<Expr>),
+    // Only `Negate` for now
+    UnOp(Operation, Box<Expr>),
+    Call(Function, Vec<Expr>),
+    // A function defined with `def`, which only the session can work out
+    UserCall(String, Vec<Expr>),
+    // `condition ? then : otherwise`, where a condition other than zero picks `then`
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr
+{
+    // Folds RPN back into the tree it was flattened from.
+    pub fn from_rpn(tokens: Vec<Token>) -> Result<Expr, CalcError>
+    {
+        let mut stack = Vec::new();
+
+        for token in tokens
+        {
+            let expr = match token
+            {
+                Token::Number(num) => Expr::Num(num),
+                Token::Variable(name) => Expr::Var(name),
+                Token::Operation(Operation::Negate) =>
+                {
+                    let operand = stack.pop().ok_or(CalcError::MissingOperand(Operation::Negate.symbol()))?;
+                    Expr::UnOp(Operation::Negate, Box::new(operand))
+                }
+                Token::Operation(Operation::Conditional) =>
+                {
+                    let missing = || CalcError::MissingOperand(Operation::Conditional.symbol());
+                    let otherwise = stack.pop().ok_or_else(missing)?;
+                    let then = stack.pop().ok_or_else(missing)?;
+                    let condition = stack.pop().ok_or_else(missing)?;
+                    Expr::Cond(Box::new(condition), Box::new(then), Box::new(otherwise))
+                }
+                Token::Operation(op) =>
+                {
+                    let right = stack.pop().ok_or(CalcError::MissingOperand(op.symbol()))?;
+                    let left = stack.pop().ok_or(CalcError::MissingOperand(op.symbol()))?;
+                    Expr::BinOp(op, Box::new(left), Box::new(right))
+                }
+                Token::Call(function, args) => Expr::Call(function, pop_args(&mut stack, args)?),
+                Token::UserCall(name, args) => Expr::UserCall(name, pop_args(&mut stack, args)?),
+                // Only calls are left once `shunting_yard` has run
+                Token::Function(_) | Token::UserFunction(_) | Token::Comma | Token::Colon => return Err(CalcError::InvalidExpression),
+            };
+            stack.push(expr);
+        }
+
+        match (stack.pop(), stack.is_empty())
+        {
+            (Some(expr), true) => Ok(expr),
+            _ => Err(CalcError::InvalidExpression),
+        }
+    }
+
+    // The tree with the operator first, like `(+ d2 (* d3 d4))`.
+    pub fn to_sexpr(&self) -> String
+    {
+        let sexpr = self.fold(|expr, operands: Vec<String>| -> Result<String, ()>
+        {
+            let head = match expr
+            {
+                Expr::Num(num) => return Ok(num.to_string()),
+                Expr::Var(name) => return Ok(name.clone()),
+                Expr::BinOp(op, ..) | Expr::UnOp(op, _) => op.symbol(),
+                Expr::Cond(..) => Operation::Conditional.symbol(),
+                Expr::Call(function, _) => function.name(),
+                Expr::UserCall(name, _) => name,
+            };
+            Ok(format!("({} {})", head, operands.join(" ")))
+        });
+        sexpr.unwrap_or_default()
+    }
+
+    // Works out every part that doesn't depend on a variable or a user function, leaving the rest as it is:
+    // `x + d2 * d3` becomes `x + d6`. Each worked-out part takes the base of its first operand. A conditional
+    // whose condition works out becomes the branch it picks, and the other one is left alone.
+    pub fn simplify(&self, settings: &Settings) -> Result<Expr, CalcError>
+    {
+        let constant = |condition: &Expr| match condition
+        {
+            Expr::Num(num) => Some(num.value != 0),
+            _ => None,
+        };
+        self.fold_branching(|expr, mut operands: Vec<Expr>|
+        {
+            if let (Expr::Cond(..), 2) = (expr, operands.len())
+            {
+                return Ok(operands.pop().expect("the branch taken"));
+            }
+            let values: Option<Vec<Number>> = operands
+                .iter()
+                .map(|operand| match operand
+                {
+                    Expr::Num(num) => Some(num.clone()),
+                    _ => None,
+                })
+                .collect();
+            let base = values.as_ref().and_then(|values| values.first()).map(|num| num.base);
+            let args: Vec<i64> = values.iter().flatten().map(|num| num.value).collect();
+
+            let folded = match (expr, base)
+            {
+                (Expr::UnOp(..), Some(_)) => Some(overflowing_neg(args[0], settings)?),
+                (Expr::BinOp(op, ..), Some(_)) => Some(op.apply(args[0], args[1], settings)?),
+                // `rand` has to stay a call to give a new value each time, and the others only fold into one value
+                (Expr::Call(function, _), Some(_)) if *function != Function::Rand && function.labels().is_empty() => Some(function.apply(&args, settings)?[0]),
+                _ => None,
+            };
+
+            Ok(match (folded, base)
+            {
+                (Some(value), Some(base)) => Expr::Num(Number { value, base }),
+                _ => expr.with_operands(operands),
+            })
+        }, constant)
+    }
+
+    // Says in words what evaluating the tree does, one step per operation in the order they happen:
+    // `multiply d3 and d4 first (higher precedence) to get d12, then add d2 and d12 to get d14.` Literals
+    // are written in their own base and worked-out values in `base`.
+    pub fn explain(&self, variables: &HashMap<String, Number>, settings: &Settings, call: &FunctionCaller, base: Base) -> Result<String, CalcError>
+    {
+        // Each step's action, the note that says why it comes when it does, and what it came to
+        let mut steps: Vec<(String, &str, String)> = Vec::new();
+
+        let (value, _) = self.fold_branching(|expr, operands: Vec<(i64, Option<usize>)>| -> Result<(i64, Option<usize>), CalcError>
+        {
+            let values: Vec<i64> = operands.iter().map(|(value, _)| *value).collect();
+            let (value, result) = match expr
+            {
+                Expr::Num(num) => return Ok((num.value, None)),
+                Expr::Var(name) => return variables.get(name).map(|num| (num.value, None)).ok_or_else(|| CalcError::UndefinedVariable(name.clone())),
+                Expr::UnOp(..) => overflowing_neg(values[0], settings).map(|value| (value, format_value(value, base)))?,
+                Expr::BinOp(op, ..) => op.apply(values[0], values[1], settings).map(|value| (value, format_value(value, base)))?,
+                Expr::Call(function, _) =>
+                {
+                    let results = function.apply(&values, settings)?;
+                    // Several values only make sense as the whole expression, as with `eval_ast`
+                    if results.len() > 1 && !std::ptr::eq(expr, self)
+                    {
+                        return Err(CalcError::MultipleValues(function.name().to_string()));
+                    }
+                    let described: Vec<String> = match function.labels()
+                    {
+                        [] => results.iter().map(|value| format_value(*value, base)).collect(),
+                        labels => labels.iter().zip(&results).map(|(label, value)| format!("{}={}", label, format_value(*value, base))).collect(),
+                    };
+                    (results[0], described.join(" "))
+                }
+                Expr::UserCall(name, _) => call(name, &values).map(|value| (value, format_value(value, base)))?,
+                // Only the branch taken was worked out
+                Expr::Cond(..) => (values[1], format_value(values[1], base)),
+            };
+
+            let shown = match expr
+            {
+                Expr::Cond(condition, then, otherwise) => vec![&**condition, if values[0] != 0 { then } else { otherwise }],
+                _ => expr.operands(),
+            };
+            let texts: Vec<String> = shown
+                .iter()
+                .zip(&values)
+                .map(|(operand, value)| match operand
+                {
+                    Expr::Num(num) => num.to_string(),
+                    Expr::Var(name) => format!("{} ({})", name, format_value(*value, variables[name].base)),
+                    _ => format_value(*value, base),
+                })
+                .collect();
+
+            // An operation under a binary one either binds tighter or must have been put in parentheses
+            if let Expr::BinOp(op, ..) | Expr::UnOp(op, _) = expr
+            {
+                for (i, (operand, (_, step))) in expr.operands().iter().zip(&operands).enumerate()
+                {
+                    if let (Expr::BinOp(inner, ..), Some(step)) = (operand, step)
+                    {
+                        steps[*step].1 = if inner.precedence() > op.precedence() && *op != Operation::Negate
+                        {
+                            " (higher precedence)"
+                        }
+                        else if inner.precedence() < op.precedence() || i == 1 || *op == Operation::Negate
+                        {
+                            " (in parentheses)"
+                        }
+                        else
+                        {
+                            ""
+                        };
+                    }
+                }
+            }
+
+            steps.push((narrate(expr, &texts), "", result));
+            Ok((value, Some(steps.len() - 1)))
+        }, |(condition, _)| Some(*condition != 0))?;
+
+        if steps.is_empty()
+        {
+            return Ok(format!("there is nothing to work out: the value is {}.", format_value(value, base)));
+        }
+        let single = steps.len() == 1;
+        let sentences: Vec<String> = steps
+            .iter()
+            .enumerate()
+            .map(|(i, (action, note, result))| match i
+            {
+                0 if !single => format!("{} first{} to get {}", action, note, result),
+                0 => format!("{}{} to get {}", action, note, result),
+                _ => format!("then {}{} to get {}", action, note, result),
+            })
+            .collect();
+        Ok(format!("{}.", sentences.join(", ")))
+    }
+
+    // A copy of this node with `operands` in place of its own.
+    fn with_operands(&self, mut operands: Vec<Expr>) -> Expr
+    {
+        match self
+        {
+            Expr::Num(_) | Expr::Var(_) => self.clone(),
+            Expr::Cond(..) =>
+            {
+                let otherwise = operands.pop().expect("three operands");
+                let then = operands.pop().expect("three operands");
+                let condition = operands.pop().expect("three operands");
+                Expr::Cond(Box::new(condition), Box::new(then), Box::new(otherwise))
+            }
+            Expr::BinOp(op, ..) =>
+            {
+                let right = operands.pop().expect("two operands");
+                let left = operands.pop().expect("two operands");
+                Expr::BinOp(*op, Box::new(left), Box::new(right))
+            }
+            Expr::UnOp(op, _) => Expr::UnOp(*op, Box::new(operands.pop().expect("one operand"))),
+            Expr::Call(function, _) => Expr::Call(*function, operands),
+            Expr::UserCall(name, _) => Expr::UserCall(name.clone(), operands),
+        }
+    }
+
+    // The direct children, left to right.
+    fn operands(&self) -> Vec<&Expr>
+    {
+        match self
+        {
+            Expr::Num(_) | Expr::Var(_) => Vec::new(),
+            Expr::BinOp(_, left, right) => vec![left, right],
+            Expr::UnOp(_, operand) => vec![operand],
+            Expr::Cond(condition, then, otherwise) => vec![condition, then, otherwise],
+            Expr::Call(_, args) | Expr::UserCall(_, args) => args.iter().collect(),
+        }
+    }
+
+    // Works through the tree bottom-up without recursing, so that a long chain such as `d1 + d1 + ...` can't
+    // overflow the stack. `combine` gets each node with what its operands came to, left to right.
+    pub fn fold<T, E>(&self, combine: impl FnMut(&Expr, Vec<T>) -> Result<T, E>) -> Result<T, E>
+    {
+        self.fold_branching(combine, |_| None)
+    }
+
+    // Like `fold`, but a conditional can skip the branch it doesn't take: once its condition is worked out,
+    // `pick` says which branch that is (`Some(true)` for `then`), and `combine` then gets the conditional with
+    // just two operands, the condition's and the branch's. `None` works out both, as `fold` does.
+    pub fn fold_branching<T, E>(&self, mut combine: impl FnMut(&Expr, Vec<T>) -> Result<T, E>, pick: impl Fn(&T) -> Option<bool>) -> Result<T, E>
+    {
+        enum Step<'e>
+        {
+            Enter(&'e Expr),
+            Leave(&'e Expr, usize),
+            // A conditional whose condition was just worked out
+            Branch(&'e Expr, &'e Expr, &'e Expr),
+        }
+
+        let mut steps = vec![Step::Enter(self)];
+        let mut results = Vec::new();
+
+        while let Some(step) = steps.pop()
+        {
+            match step
+            {
+                Step::Enter(expr @ Expr::Cond(condition, then, otherwise)) =>
+                {
+                    steps.push(Step::Branch(expr, then, otherwise));
+                    steps.push(Step::Enter(condition));
+                }
+                Step::Branch(expr, then, otherwise) => match pick(results.last().expect("the condition's result"))
+                {
+                    Some(taken) =>
+                    {
+                        steps.push(Step::Leave(expr, 2));
+                        steps.push(Step::Enter(if taken { then } else { otherwise }));
+                    }
+                    None =>
+                    {
+                        steps.push(Step::Leave(expr, 3));
+                        steps.push(Step::Enter(otherwise));
+                        steps.push(Step::Enter(then));
+                    }
+                },
+                Step::Enter(expr) =>
+                {
+                    let operands = expr.operands();
+                    steps.push(Step::Leave(expr, operands.len()));
+                    // Reversed, so that the leftmost operand is worked out first
+                    steps.extend(operands.into_iter().rev().map(Step::Enter));
+                }
+                Step::Leave(expr, count) =>
+                {
+                    let operands = results.split_off(results.len() - count);
+                    results.push(combine(expr, operands)?);
+                }
+            }
+        }
+
+        Ok(results.pop().expect("the root leaves one result"))
+    }
+}
+
+// Dropping a deep tree node by node would recurse as deep as it goes, so the children are unhooked first.
+impl Drop for Expr
+{
+    fn drop(&mut self)
+    {
+        let mut pending = Vec::new();
+        take_children(self, &mut pending);
+        while let Some(mut expr) = pending.pop()
+        {
+            take_children(&mut expr, &mut pending);
+        }
+    }
+}
+
+fn take_children(expr: &mut Expr, pending: &mut Vec<Expr>)
+{
+    let mut take = |child: &mut Expr| pending.push(std::mem::replace(child, Expr::Var(String::new())));
+    match expr
+    {
+        Expr::BinOp(_, left, right) =>
+        {
+            take(left);
+            take(right);
+        }
+        Expr::UnOp(_, operand) => take(operand),
+        Expr::Cond(condition, then, otherwise) =>
+        {
+            take(condition);
+            take(then);
+            take(otherwise);
+        }
+        Expr::Call(_, args) | Expr::UserCall(_, args) => pending.append(args),
+        Expr::Num(_) | Expr::Var(_) => {}
+    }
+}
+
+// What applying a node to operands written as `operands` does, like `divide d10 by d2`.
+fn narrate(expr: &Expr, operands: &[String]) -> String
+{
+    let (a, b) = (operands.first().map_or("", String::as_str), operands.get(1).map_or("", String::as_str));
+    match expr
+    {
+        Expr::BinOp(Operation::Add, ..) => format!("add {} and {}", a, b),
+        Expr::BinOp(Operation::Subtract, ..) => format!("subtract {} from {}", b, a),
+        Expr::BinOp(Operation::Multiply, ..) => format!("multiply {} and {}", a, b),
+        Expr::BinOp(Operation::Divide, ..) => format!("divide {} by {}", a, b),
+        Expr::BinOp(Operation::Modulo, ..) => format!("take the remainder of {} divided by {}", a, b),
+        Expr::BinOp(Operation::ShiftLeft, ..) => format!("shift {} left by {}", a, b),
+        Expr::BinOp(Operation::ShiftRight, ..) => format!("shift {} right by {}", a, b),
+        Expr::BinOp(Operation::Less, ..) => format!("check whether {} is less than {}", a, b),
+        Expr::BinOp(Operation::Greater, ..) => format!("check whether {} is greater than {}", a, b),
+        Expr::BinOp(Operation::BitAnd, ..) => format!("AND {} with {}", a, b),
+        Expr::BinOp(Operation::BitXor, ..) => format!("XOR {} with {}", a, b),
+        Expr::BinOp(Operation::BitOr, ..) => format!("OR {} with {}", a, b),
+        Expr::BinOp(op, ..) => format!("apply {} to {} and {}", op.symbol(), a, b),
+        Expr::UnOp(..) => format!("negate {}", a),
+        Expr::Cond(..) => format!("pick {} since the condition is {}", b, a),
+        Expr::Call(function, _) => format!("call {} with {}", function.name(), operands.join(", ")),
+        Expr::UserCall(name, _) => format!("call {} with {}", name, operands.join(", ")),
+        Expr::Num(num) => num.to_string(),
+        Expr::Var(name) => name.clone(),
+    }
+}
+
+// Only i64::MIN has no negation, or every value but zero when unsigned; `settings.overflow` says what it
+// gives, as for `+`, `-`, `*` and `/`.
+pub(crate) fn overflowing_neg(value: i64, settings: &Settings) -> Result<i64, CalcError>
+{
+    if settings.unsigned
+    {
+        return match settings.overflow
+        {
+            OverflowMode::Checked if value != 0 => Err(CalcError::Overflow("negation".to_string())),
+            OverflowMode::Saturating => Ok(0),
+            _ => Ok(value.wrapping_neg()),
+        };
+    }
+    match settings.overflow
+    {
+        OverflowMode::Checked => value.checked_neg().ok_or(CalcError::Overflow("negation".to_string())),
+        OverflowMode::Wrapping => Ok(value.wrapping_neg()),
+        OverflowMode::Saturating => Ok(value.saturating_neg()),
+    }
+}
+
+fn pop_args(stack: &mut Vec<Expr>, count: usize) -> Result<Vec<Expr>, CalcError>
+{
+    let start = stack.len().checked_sub(count).ok_or(CalcError::InvalidExpression)?;
+    Ok(stack.split_off(start))
+}
+
+// Evaluates a tree. The outermost node may be a function giving several values, such as `divmod`; anywhere
+// else that is an error, since there is no single value to carry on with.
+pub fn eval_ast(expr: &Expr, variables: &HashMap<String, Number>, settings: &Settings, call: &FunctionCaller) -> Result<Evaluation, CalcError>
+{
+    let deadline = settings.timeout.map(|timeout| Instant::now() + timeout);
+    let mut nodes = 0usize;
+    let mut operations = 0;
+
+    let mut value = |expr: &Expr, operands: Vec<i64>| -> Result<i64, CalcError>
+    {
+        // Reading the clock on every node would cost more than most operations
+        if nodes.is_multiple_of(256) && deadline.is_some_and(|deadline| Instant::now() > deadline)
+        {
+            return Err(CalcError::Timeout);
+        }
+        nodes += 1;
+        if !operands.is_empty()
+        {
+            operations += 1;
+        }
+
+        match expr
+        {
+            Expr::Num(num) => Ok(num.value),
+            Expr::Var(name) => variables.get(name).map(|num| num.value).ok_or_else(|| CalcError::UndefinedVariable(name.clone())),
+            Expr::UnOp(..) => overflowing_neg(operands[0], settings),
+            Expr::BinOp(op, ..) => op.apply(operands[0], operands[1], settings),
+            Expr::Call(function, _) => match function.apply(&operands, settings)?[..]
+            {
+                [value] => Ok(value),
+                _ => Err(CalcError::MultipleValues(function.name().to_string())),
+            },
+            Expr::UserCall(name, _) => call(name, &operands),
+            // With the other branch skipped, the operands are the condition and the branch taken
+            Expr::Cond(..) => Ok(operands[1]),
+        }
+    };
+
+    // Conditionals only work out the branch they take, in a call's arguments as anywhere else
+    let taken = |condition: &i64| Some(*condition != 0);
+    let values = match expr
+    {
+        Expr::Call(function, args) =>
+        {
+            let args = args.iter().map(|arg| arg.fold_branching(&mut value, taken)).collect::<Result<Vec<_>, _>>()?;
+            operations += 1;
+            function.apply(&args, settings)?
+        }
+        _ => vec![expr.fold_branching(&mut value, taken)?],
+    };
+    Ok(Evaluation { values, operations })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::session::Session;
+
+    #[test]
+    fn negating_the_minimum_follows_the_overflow_mode()
+    {
+        let mode = |overflow| Settings { overflow, ..Settings::default() };
+        assert_eq!(overflowing_neg(i64::MIN, &mode(OverflowMode::Checked)), Err(CalcError::Overflow("negation".into())));
+        assert_eq!(overflowing_neg(i64::MIN, &mode(OverflowMode::Wrapping)), Ok(i64::MIN));
+        assert_eq!(overflowing_neg(i64::MIN, &mode(OverflowMode::Saturating)), Ok(i64::MAX));
+        assert_eq!(overflowing_neg(i64::MIN + 1, &mode(OverflowMode::Checked)), Ok(i64::MAX));
+
+        // Arithmetic is 64-bit whatever the width, which only says how results are inspected, so a narrower
+        // width's own minimum negates without overflow
+        let mut session = Session::new();
+        session.settings_mut().width = 8;
+        assert_eq!(session.eval("-(d-128)").map(|result| result.number.value), Ok(128));
+        assert_eq!(session.eval(&format!("-(d{})", i64::MIN)).map(|result| result.number.value), Err(CalcError::Overflow("negation".into())));
+    }
+
+    #[test]
+    fn conditionals_in_call_arguments_take_one_branch()
+    {
+        let mut session = Session::new();
+        let mut eval = |input: &str| session.eval(input).map(|result| result.to_string());
+        assert_eq!(eval("sum(d0 ? d1 : d2) d"), Ok("d2".into()));
+        assert_eq!(eval("sum(d5 ? d1 : d2) d"), Ok("d1".into()));
+        assert_eq!(eval("width(d0 ? d1 : hFF) d"), Ok("d8".into()));
+        assert_eq!(eval("divmod(d0 ? d7 : d9, d2)"), Ok("q=d4 r=d1".into()));
+        // The branch not taken is never worked out, so it can't fail
+        assert_eq!(eval("sum(d1 ? d1 : d1 / d0) d"), Ok("d1".into()));
+        assert_eq!(eval("divmod(d1 ? d7 : d1 / d0, d2)"), Ok("q=d3 r=d1".into()));
+        assert_eq!(eval("sum(d0 ? d1 : d1 / d0)"), Err(CalcError::DivisionByZero));
+    }
+}
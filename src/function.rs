@@ -0,0 +1,452 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::CalcError;
+use crate::number::{fits_width, format_with, Base, FormatOptions};
+use crate::settings::{ModStyle, Settings, WIDTHS};
+use crate::token::Operation;
+
+// Built-in functions, called as `name(argument, ...)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function
+{
+    DivMod,
+    CeilPow2,
+    FloorPow2,
+    NibSwap,
+    Sum,
+    Product,
+    Rand,
+    Width,
+    Hamming,
+    IsPrime,
+    BitRev,
+    Assert,
+}
+
+// State of the generator behind `rand`, shared by every session in the process. Zero until it is
+// first used or seeded.
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+// Makes `rand` give the same sequence on every run that uses the same seed.
+pub fn seed_random(seed: u64)
+{
+    // Zero would read as "not seeded yet", so it is nudged to one
+    RANDOM_STATE.store(seed.max(1), Ordering::Relaxed);
+}
+
+// Trial division while it is quick, then Miller-Rabin with the first twelve primes as witnesses, which is
+// exact for every 64-bit value.
+fn is_prime(n: u64) -> bool
+{
+    if n < 2
+    {
+        return false;
+    }
+    for p in [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37]
+    {
+        if n.is_multiple_of(p)
+        {
+            return n == p;
+        }
+    }
+    if n < 1 << 20
+    {
+        return (41..).step_by(2).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d));
+    }
+
+    let mul = |a: u64, b: u64| (u128::from(a) * u128::from(b) % u128::from(n)) as u64;
+    let pow = |mut base: u64, mut exp: u64|
+    {
+        let mut result = 1;
+        while exp > 0
+        {
+            if exp & 1 == 1
+            {
+                result = mul(result, base);
+            }
+            base = mul(base, base);
+            exp >>= 1;
+        }
+        result
+    };
+    // n - 1 is odd * 2^twos
+    let twos = (n - 1).trailing_zeros();
+    let odd = (n - 1) >> twos;
+    [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37].into_iter().all(|witness|
+    {
+        let mut x = pow(witness, odd);
+        if x == 1 || x == n - 1
+        {
+            return true;
+        }
+        for _ in 1..twos
+        {
+            x = mul(x, x);
+            if x == n - 1
+            {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+// splitmix64: small and plenty for test vectors, but not for anything that has to be unpredictable.
+fn next_random() -> u64
+{
+    if RANDOM_STATE.load(Ordering::Relaxed) == 0
+    {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_nanos() as u64);
+        let _ = RANDOM_STATE.compare_exchange(0, nanos.max(1), Ordering::Relaxed, Ordering::Relaxed);
+    }
+    let mut z = RANDOM_STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+impl Function
+{
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "divmod" => Some(Function::DivMod),
+            "ceilpow2" => Some(Function::CeilPow2),
+            "floorpow2" => Some(Function::FloorPow2),
+            "nibswap" => Some(Function::NibSwap),
+            "sum" => Some(Function::Sum),
+            "product" => Some(Function::Product),
+            "rand" => Some(Function::Rand),
+            "width" => Some(Function::Width),
+            "hamming" => Some(Function::Hamming),
+            "isprime" => Some(Function::IsPrime),
+            "bitrev" => Some(Function::BitRev),
+            "assert" => Some(Function::Assert),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Function::DivMod => "divmod",
+            Function::CeilPow2 => "ceilpow2",
+            Function::FloorPow2 => "floorpow2",
+            Function::NibSwap => "nibswap",
+            Function::Sum => "sum",
+            Function::Product => "product",
+            Function::Rand => "rand",
+            Function::Width => "width",
+            Function::Hamming => "hamming",
+            Function::IsPrime => "isprime",
+            Function::BitRev => "bitrev",
+            Function::Assert => "assert",
+        }
+    }
+
+    // How many arguments it takes, or `None` if it takes any number of them.
+    pub fn arity(&self) -> Option<usize>
+    {
+        match self
+        {
+            Function::DivMod | Function::NibSwap | Function::Rand | Function::Hamming | Function::BitRev | Function::Assert => Some(2),
+            Function::CeilPow2 | Function::FloorPow2 | Function::Width | Function::IsPrime => Some(1),
+            Function::Sum | Function::Product => None,
+        }
+    }
+
+    // What each value is called when printed, for functions that give more than one. Those can only be used
+    // on their own, since there is no single value to carry into the rest of an expression.
+    pub fn labels(&self) -> &'static [&'static str]
+    {
+        match self
+        {
+            Function::DivMod => &["q", "r"],
+            _ => &[],
+        }
+    }
+
+    pub fn apply(&self, args: &[i64], settings: &Settings) -> Result<Vec<i64>, CalcError>
+    {
+        match self
+        {
+            Function::DivMod =>
+            {
+                let (left, right) = (args[0], args[1]);
+                if right == 0
+                {
+                    return Err(CalcError::DivisionByZero);
+                }
+                if settings.unsigned
+                {
+                    return Ok(vec![Operation::Divide.apply(left, right, settings)?, Operation::Modulo.apply(left, right, settings)?]);
+                }
+                // The quotient rounds the same way as `%` so that `q * right + r` is always `left`
+                let mut quotient = left.wrapping_div(right);
+                let mut remainder = left.wrapping_rem(right);
+                if settings.mod_style == ModStyle::Floored && remainder != 0 && (remainder < 0) != (right < 0)
+                {
+                    quotient -= 1;
+                    remainder += right;
+                }
+                Ok(vec![quotient, remainder])
+            }
+            Function::CeilPow2 | Function::FloorPow2 =>
+            {
+                let value = args[0];
+                if value == 0 || (value < 0 && !settings.unsigned)
+                {
+                    return Err(CalcError::InvalidArgument(self.name().to_string(), value));
+                }
+                let overflow = || CalcError::Overflow(self.name().to_string());
+                let power = match self
+                {
+                    // The next power of two above i64::MAX is 2^63, which only fits unsigned
+                    Function::CeilPow2 => (value as u64).checked_next_power_of_two().filter(|power| settings.unsigned || *power <= i64::MAX as u64).ok_or_else(overflow)? as i64,
+                    _ => (1u64 << (63 - (value as u64).leading_zeros())) as i64,
+                };
+                // A power past the session's width, like ceilpow2(d200) at 8 bits, doesn't fit the register it is for
+                if !fits_width(power, settings.width)
+                {
+                    return Err(overflow());
+                }
+                Ok(vec![power])
+            }
+            Function::NibSwap =>
+            {
+                // Reverses the order of the 4-bit nibbles in the low `width` bits, with anything above them dropped
+                let (value, width) = (args[0], args[1]);
+                if width <= 0 || width > 64 || width % 4 != 0
+                {
+                    return Err(CalcError::InvalidArgument(self.name().to_string(), width));
+                }
+                let mut nibbles = value as u64;
+                let mut swapped = 0u64;
+                for _ in 0..width / 4
+                {
+                    swapped = (swapped << 4) | (nibbles & 0xF);
+                    nibbles >>= 4;
+                }
+                Ok(vec![swapped as i64])
+            }
+            Function::BitRev =>
+            {
+                // Reverses the order of the low `width` bits, as `reverse_bits` does on the unsigned type that wide
+                let (value, width) = (args[0], args[1]);
+                let width = u32::try_from(width).ok().filter(|width| WIDTHS.contains(width)).ok_or_else(|| CalcError::InvalidArgument(self.name().to_string(), width))?;
+                if !fits_width(value, width)
+                {
+                    return Err(CalcError::InvalidArgument(self.name().to_string(), value));
+                }
+                Ok(vec![((value as u64).reverse_bits() >> (64 - width)) as i64])
+            }
+            // Folded with the operators themselves, so the arguments combine exactly as if written out with `+` or `*`
+            Function::Sum => Ok(vec![args[1..].iter().try_fold(args[0], |total, &arg| Operation::Add.apply(total, arg, settings))?]),
+            Function::Product => Ok(vec![args[1..].iter().try_fold(args[0], |total, &arg| Operation::Multiply.apply(total, arg, settings))?]),
+            Function::Width =>
+            {
+                // The fewest bits that hold the value: unsigned for zero and up (zero needs none), two's complement
+                // with its sign bit for negative values, so `d-128` takes 8 and `d-129` takes 9. An unsigned session
+                // has no negative values, so there it is always the unsigned count.
+                let value = args[0];
+                let bits = if value < 0 && !settings.unsigned { 65 - (!value).leading_zeros() } else { 64 - value.leading_zeros() };
+                Ok(vec![i64::from(bits)])
+            }
+            // Counted over all 64 bits, so a negative value brings its sign-extended ones with it
+            Function::Hamming => Ok(vec![i64::from((args[0] ^ args[1]).count_ones())]),
+            Function::IsPrime =>
+            {
+                // Negative values aren't prime; an unsigned session has none, only values above i64::MAX
+                let value = if settings.unsigned { Some(args[0] as u64) } else { u64::try_from(args[0]).ok() };
+                Ok(vec![i64::from(value.is_some_and(is_prime))])
+            }
+            Function::Assert =>
+            {
+                // The value it checked, so an assertion can stand in for the expression it tests
+                let (actual, expected) = (args[0], args[1]);
+                if actual != expected
+                {
+                    let options = FormatOptions { unsigned: settings.unsigned, ..FormatOptions::default() };
+                    return Err(CalcError::AssertionFailed(format_with(actual, Base::Decimal, &options), format_with(expected, Base::Decimal, &options)));
+                }
+                Ok(vec![actual])
+            }
+            Function::Rand =>
+            {
+                // Anywhere from `low` to `high`, both included
+                let (low, high) = (args[0], args[1]);
+                if low > high
+                {
+                    return Err(CalcError::InvalidArgument(self.name().to_string(), high));
+                }
+                let span = high.wrapping_sub(low) as u64;
+                let offset = match span.checked_add(1)
+                {
+                    Some(count) => next_random() % count,
+                    // The whole i64 range
+                    None => next_random(),
+                };
+                Ok(vec![low.wrapping_add(offset as i64)])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn apply(function: Function, args: &[i64]) -> Result<Vec<i64>, CalcError>
+    {
+        function.apply(args, &Settings::default())
+    }
+
+    #[test]
+    fn width_counts_the_bits_a_value_needs()
+    {
+        assert_eq!(apply(Function::Width, &[0]), Ok(vec![0]));
+        assert_eq!(apply(Function::Width, &[1]), Ok(vec![1]));
+        assert_eq!(apply(Function::Width, &[255]), Ok(vec![8]));
+        assert_eq!(apply(Function::Width, &[256]), Ok(vec![9]));
+        assert_eq!(apply(Function::Width, &[i64::MAX]), Ok(vec![63]));
+        // Negative values keep a sign bit
+        assert_eq!(apply(Function::Width, &[-1]), Ok(vec![1]));
+        assert_eq!(apply(Function::Width, &[-128]), Ok(vec![8]));
+        assert_eq!(apply(Function::Width, &[-129]), Ok(vec![9]));
+        assert_eq!(apply(Function::Width, &[i64::MIN]), Ok(vec![64]));
+        let unsigned = Settings { unsigned: true, ..Settings::default() };
+        assert_eq!(Function::Width.apply(&[-1], &unsigned), Ok(vec![64]));
+    }
+
+    #[test]
+    fn hamming_counts_the_differing_bits()
+    {
+        assert_eq!(apply(Function::Hamming, &[0xFF, 0xF0]), Ok(vec![4]));
+        assert_eq!(apply(Function::Hamming, &[0b1010, 0b0101]), Ok(vec![4]));
+        assert_eq!(apply(Function::Hamming, &[0xDEAD, 0xDEAD]), Ok(vec![0]));
+        assert_eq!(apply(Function::Hamming, &[0, 0]), Ok(vec![0]));
+        // Over all 64 bits, sign extension included
+        assert_eq!(apply(Function::Hamming, &[-1, 0]), Ok(vec![64]));
+        assert_eq!(apply(Function::Hamming, &[i64::MIN, i64::MAX]), Ok(vec![64]));
+    }
+
+    #[test]
+    fn isprime_by_trial_division()
+    {
+        assert_eq!(apply(Function::IsPrime, &[97]), Ok(vec![1]));
+        assert_eq!(apply(Function::IsPrime, &[91]), Ok(vec![0]));
+        assert_eq!(apply(Function::IsPrime, &[0]), Ok(vec![0]));
+        assert_eq!(apply(Function::IsPrime, &[1]), Ok(vec![0]));
+        assert_eq!(apply(Function::IsPrime, &[2]), Ok(vec![1]));
+        assert_eq!(apply(Function::IsPrime, &[3]), Ok(vec![1]));
+        assert_eq!(apply(Function::IsPrime, &[4]), Ok(vec![0]));
+        assert_eq!(apply(Function::IsPrime, &[-7]), Ok(vec![0]));
+        // The largest prime below 2^31, and a square of a prime
+        assert_eq!(apply(Function::IsPrime, &[2_147_483_647]), Ok(vec![1]));
+        assert_eq!(apply(Function::IsPrime, &[65_521 * 65_521]), Ok(vec![0]));
+    }
+
+    #[test]
+    fn bitrev_within_a_width()
+    {
+        assert_eq!(apply(Function::BitRev, &[0x01, 8]), Ok(vec![0x80]));
+        assert_eq!(apply(Function::BitRev, &[0b1101_0000, 8]), Ok(vec![0b0000_1011]));
+        assert_eq!(apply(Function::BitRev, &[0xFF, 8]), Ok(vec![0xFF]));
+        assert_eq!(apply(Function::BitRev, &[0x0001, 16]), Ok(vec![0x8000]));
+        assert_eq!(apply(Function::BitRev, &[0x00F0, 16]), Ok(vec![0x0F00]));
+        assert_eq!(apply(Function::BitRev, &[0x1234, 16]), Ok(vec![0x2C48]));
+
+        assert_eq!(apply(Function::BitRev, &[0x100, 8]), Err(CalcError::InvalidArgument("bitrev".into(), 0x100)));
+        assert_eq!(apply(Function::BitRev, &[1, 12]), Err(CalcError::InvalidArgument("bitrev".into(), 12)));
+    }
+
+    #[test]
+    fn assert_passes_or_names_both_values()
+    {
+        assert_eq!(apply(Function::Assert, &[4, 4]), Ok(vec![4]));
+        assert_eq!(apply(Function::Assert, &[4, 5]), Err(CalcError::AssertionFailed("d4".into(), "d5".into())));
+
+        let mut session = crate::session::Session::new();
+        let mut eval = |input| session.eval(input).map(|result| result.to_string());
+        assert_eq!(eval("assert(d2 + d2, d4)"), Ok("d4".into()));
+        // An output base at the end of an argument is allowed and changes nothing
+        assert_eq!(eval("assert(d2 + d2 d, d4)"), Ok("d4".into()));
+        assert_eq!(eval("assert(hF0 | h0F h, d255) h"), Ok("hFF".into()));
+        assert_eq!(eval("assert(d2 + d2 d, d5)"), Err(CalcError::AssertionFailed("d4".into(), "d5".into())));
+        assert_eq!(eval("assert(d1 / d0, d1)"), Err(CalcError::DivisionByZero));
+        // Outside a call's arguments a lone prefix is still a number without digits
+        assert_eq!(eval("(d2 + d2 d)"), Err(CalcError::MissingDigits(Base::Decimal)));
+        assert_eq!(CalcError::AssertionFailed("d4".into(), "d5".into()).to_string(), "Assertion failed: d4 != d5");
+    }
+
+    #[test]
+    fn powers_of_two_at_the_boundaries()
+    {
+        assert_eq!(apply(Function::CeilPow2, &[1000]), Ok(vec![1024]));
+        assert_eq!(apply(Function::FloorPow2, &[1000]), Ok(vec![512]));
+        assert_eq!(apply(Function::CeilPow2, &[1024]), Ok(vec![1024]));
+        assert_eq!(apply(Function::FloorPow2, &[1024]), Ok(vec![1024]));
+        assert_eq!(apply(Function::CeilPow2, &[1025]), Ok(vec![2048]));
+        assert_eq!(apply(Function::CeilPow2, &[1]), Ok(vec![1]));
+        assert_eq!(apply(Function::FloorPow2, &[1]), Ok(vec![1]));
+        assert_eq!(apply(Function::FloorPow2, &[i64::MAX]), Ok(vec![1 << 62]));
+
+        assert_eq!(apply(Function::CeilPow2, &[0]), Err(CalcError::InvalidArgument("ceilpow2".into(), 0)));
+        assert_eq!(apply(Function::FloorPow2, &[0]), Err(CalcError::InvalidArgument("floorpow2".into(), 0)));
+        assert_eq!(apply(Function::CeilPow2, &[-8]), Err(CalcError::InvalidArgument("ceilpow2".into(), -8)));
+        assert_eq!(apply(Function::CeilPow2, &[(1 << 62) + 1]), Err(CalcError::Overflow("ceilpow2".into())));
+
+        // Past the session's width is an overflow too
+        let narrow = Settings { width: 8, ..Settings::default() };
+        assert_eq!(Function::CeilPow2.apply(&[100], &narrow), Ok(vec![128]));
+        assert_eq!(Function::CeilPow2.apply(&[200], &narrow), Err(CalcError::Overflow("ceilpow2".into())));
+        assert_eq!(Function::FloorPow2.apply(&[300], &narrow), Err(CalcError::Overflow("floorpow2".into())));
+
+        // Unsigned, 2^63 is in range
+        let unsigned = Settings { unsigned: true, ..Settings::default() };
+        assert_eq!(Function::CeilPow2.apply(&[(1 << 62) + 1], &unsigned), Ok(vec![i64::MIN]));
+        assert_eq!(Function::FloorPow2.apply(&[-1], &unsigned), Ok(vec![i64::MIN]));
+        assert_eq!(Function::CeilPow2.apply(&[-1], &unsigned), Err(CalcError::Overflow("ceilpow2".into())));
+    }
+
+    #[test]
+    fn nibswap_at_several_widths()
+    {
+        assert_eq!(apply(Function::NibSwap, &[0x12, 8]), Ok(vec![0x21]));
+        assert_eq!(apply(Function::NibSwap, &[0x1234, 16]), Ok(vec![0x4321]));
+        assert_eq!(apply(Function::NibSwap, &[0x1234_5678, 32]), Ok(vec![0x8765_4321]));
+        assert_eq!(apply(Function::NibSwap, &[0x0123_4567_89AB_CDEF, 64]), Ok(vec![0xFEDC_BA98_7654_3210u64 as i64]));
+        // Anything above the width is dropped
+        assert_eq!(apply(Function::NibSwap, &[0xAB12, 8]), Ok(vec![0x21]));
+
+        assert_eq!(apply(Function::NibSwap, &[0x12, 6]), Err(CalcError::InvalidArgument("nibswap".into(), 6)));
+        assert_eq!(apply(Function::NibSwap, &[0x12, 0]), Err(CalcError::InvalidArgument("nibswap".into(), 0)));
+        assert_eq!(apply(Function::NibSwap, &[0x12, 68]), Err(CalcError::InvalidArgument("nibswap".into(), 68)));
+    }
+
+    #[test]
+    fn sum_and_product_take_any_number_of_arguments()
+    {
+        assert_eq!(apply(Function::Sum, &[2, 3]), Ok(vec![5]));
+        assert_eq!(apply(Function::Sum, &[1, 2, 3, 4, 5]), Ok(vec![15]));
+        assert_eq!(apply(Function::Product, &[2, 3]), Ok(vec![6]));
+        assert_eq!(apply(Function::Product, &[1, 2, 3, 4, 5]), Ok(vec![120]));
+        assert_eq!(apply(Function::Sum, &[7]), Ok(vec![7]));
+        assert_eq!(apply(Function::Product, &[7]), Ok(vec![7]));
+        // Combined the way `+` and `*` would, overflow included
+        assert_eq!(apply(Function::Sum, &[i64::MAX, 1]), Err(CalcError::Overflow("addition".into())));
+
+        let mut session = crate::session::Session::new();
+        let mut eval = |input| session.eval(input).map(|result| result.to_string());
+        assert_eq!(eval("sum(d1, d2, d3, d4, d5) d"), Ok("d15".into()));
+        assert_eq!(eval("product(d1, d2, d3, d4, d5) d"), Ok("d120".into()));
+        assert_eq!(eval("sum(hF) d"), Ok("d15".into()));
+        assert_eq!(eval("sum()"), Err(CalcError::EmptyParentheses));
+        assert_eq!(eval("product()"), Err(CalcError::EmptyParentheses));
+        assert_eq!(eval("sum(d1,)"), Err(CalcError::InvalidExpression));
+    }
+}
@@ -0,0 +1,331 @@
+use std::borrow::Cow;
+
+use better_dev_calc::{closing_bracket, is_base_prefix, tokenize_spans_lenient, Operation, Settings, Token};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::COMMANDS;
+
+const RESET: &str = "\x1b[0m";
+const PREFIX: &str = "\x1b[36m";
+const DIGITS: &str = "\x1b[33m";
+const OPERATOR: &str = "\x1b[35m";
+const COMMAND: &str = "\x1b[32m";
+const DIM: &str = "\x1b[2m";
+const ERROR: &str = "\x1b[1;31m";
+const MATCH: &str = "\x1b[1;34m";
+
+// Tab completion, inline hints and syntax highlighting for the REPL's input line.
+pub struct CalcHelper
+{
+    // rustyline owns the helper, so the REPL copies the names in after every line
+    pub variables: Vec<String>,
+    // Likewise, since commands like `:base` and `:accept-commas` change how the line is read
+    pub settings: Settings,
+    color: bool,
+}
+
+impl CalcHelper
+{
+    pub fn new(color: bool, settings: Settings) -> Self
+    {
+        CalcHelper { variables: Vec::new(), settings, color }
+    }
+
+    // Returns where the word ending at `pos` starts, and every name it could be the beginning of.
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>)
+    {
+        let typed = &line[..pos];
+
+        // Commands are only completed as the first word of the line
+        let trimmed = typed.trim_start();
+        if trimmed.starts_with(':') && !trimmed.contains(char::is_whitespace)
+        {
+            let names = COMMANDS.iter().map(|(name, _)| name).filter(|name| name.starts_with(trimmed)).map(|name| name.to_string());
+            return (pos - trimmed.len(), names.collect());
+        }
+
+        let start = typed
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '_'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &typed[start..];
+        if word.is_empty()
+        {
+            return (start, Vec::new());
+        }
+
+        let names = self.variables.iter().filter(|name| name.starts_with(word)).cloned();
+        (start, names.collect())
+    }
+}
+
+impl Completer for CalcHelper
+{
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)>
+    {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for CalcHelper
+{
+    type Hint = String;
+
+    // Shows the rest of the name when only one command or variable fits what is typed so far.
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String>
+    {
+        if pos < line.len()
+        {
+            return None;
+        }
+
+        match self.candidates(line, pos)
+        {
+            (start, names) if names.len() == 1 => Some(names[0][pos - start..].to_string()).filter(|rest| !rest.is_empty()),
+            _ => None,
+        }
+    }
+}
+
+fn paint(out: &mut String, style: &str, text: &str)
+{
+    if text.is_empty()
+    {
+        return;
+    }
+    out.push_str(style);
+    out.push_str(text);
+    out.push_str(RESET);
+}
+
+// Byte offsets of the brackets that have no partner of their own kind, ignoring any comment.
+fn unmatched_parens(line: &str) -> Vec<usize>
+{
+    let code = line.split_once('#').map_or(line, |(code, _)| code);
+    let mut open = Vec::new();
+    let mut unmatched = Vec::new();
+
+    for (i, c) in code.char_indices()
+    {
+        match c
+        {
+            '(' | '[' | '{' => open.push((i, c)),
+            ')' | ']' | '}' => match open.pop()
+            {
+                Some((_, kind)) if closing_bracket(kind) == c => {}
+                // `( d5 ]`: neither half has a partner
+                Some((start, _)) => unmatched.extend([start, i]),
+                None => unmatched.push(i),
+            },
+            _ => {}
+        }
+    }
+
+    unmatched.extend(open.into_iter().map(|(i, _)| i));
+    unmatched
+}
+
+// The bracket at the cursor (or just before it, as after typing one) and its partner, ignoring any comment.
+fn matching_parens(line: &str, pos: usize) -> Option<(usize, usize)>
+{
+    let code = line.split_once('#').map_or(line, |(code, _)| code).as_bytes();
+    let at = [pos, pos.wrapping_sub(1)].into_iter().find(|&i| matches!(code.get(i), Some(b'(' | b')' | b'[' | b']' | b'{' | b'}')))?;
+
+    let mut depth = 0;
+    if matches!(code[at], b'(' | b'[' | b'{')
+    {
+        for (i, &c) in code.iter().enumerate().skip(at)
+        {
+            match c
+            {
+                b'(' | b'[' | b'{' => depth += 1,
+                b')' | b']' | b'}' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0
+            {
+                return Some((at, i));
+            }
+        }
+    }
+    else
+    {
+        for i in (0..=at).rev()
+        {
+            match code[i]
+            {
+                b')' | b']' | b'}' => depth += 1,
+                b'(' | b'[' | b'{' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0
+            {
+                return Some((i, at));
+            }
+        }
+    }
+    None
+}
+
+// Between tokens there is only whitespace and what the tokenizer rejected or left out. A lone base letter is
+// either a number still being typed or an argument's output base, so it is painted as a prefix.
+fn paint_gap(out: &mut String, gap: &str)
+{
+    let left = gap.trim();
+    let start = gap.len() - gap.trim_start().len();
+    out.push_str(&gap[..start]);
+    let style = if left.len() == 1 && left.starts_with(is_base_prefix) { PREFIX } else { ERROR };
+    paint(out, style, left);
+    out.push_str(&gap[start + left.len()..]);
+}
+
+// Colors a possibly half-typed line from what `tokenize_spans_lenient` makes of it under the session's
+// settings, so it reads numbers exactly as evaluation will. Anything it reports is painted as an error.
+fn highlight_line(line: &str, pos: usize, settings: &Settings) -> String
+{
+    let mut out = String::with_capacity(line.len() * 2);
+
+    let trimmed = line.trim_start();
+    if trimmed.starts_with(':')
+    {
+        let end = line.len() - trimmed.len() + trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+        paint(&mut out, COMMAND, &line[..end]);
+        out.push_str(&line[end..]);
+        return out;
+    }
+
+    let (code, comment) = line.split_once('#').map_or((line, None), |(code, comment)| (code, Some(comment)));
+    let unmatched = unmatched_parens(line);
+    let matched = matching_parens(line, pos);
+    let (tokens, _) = tokenize_spans_lenient(code, settings);
+    let mut done = 0;
+
+    for (token, span) in tokens
+    {
+        paint_gap(&mut out, &code[done..span.start]);
+
+        let text = &code[span.clone()];
+        match token
+        {
+            Token::Number(num) =>
+            {
+                let prefix = num.base.prefix().len_utf8();
+                if text.starts_with(num.base.prefix())
+                {
+                    paint(&mut out, PREFIX, &text[..prefix]);
+                    paint(&mut out, DIGITS, &text[prefix..]);
+                }
+                // A suffix literal like `FFh`
+                else if text.ends_with(num.base.prefix())
+                {
+                    paint(&mut out, DIGITS, &text[..text.len() - prefix]);
+                    paint(&mut out, PREFIX, &text[text.len() - prefix..]);
+                }
+                else
+                {
+                    paint(&mut out, DIGITS, text);
+                }
+            }
+            Token::Operation(Operation::OpenParen | Operation::CloseParen) =>
+            {
+                if unmatched.contains(&span.start)
+                {
+                    paint(&mut out, ERROR, text);
+                }
+                else if matched.is_some_and(|(open, close)| span.start == open || span.start == close)
+                {
+                    paint(&mut out, MATCH, text);
+                }
+                else
+                {
+                    out.push_str(text);
+                }
+            }
+            Token::Operation(_) | Token::Colon => paint(&mut out, OPERATOR, text),
+            _ => out.push_str(text),
+        }
+        done = span.end;
+    }
+
+    paint_gap(&mut out, &code[done..]);
+
+    if let Some(comment) = comment
+    {
+        paint(&mut out, DIM, &line[code.len()..code.len() + 1 + comment.len()]);
+    }
+    out
+}
+
+impl Highlighter for CalcHelper
+{
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str>
+    {
+        if !self.color
+        {
+            return Cow::Borrowed(line);
+        }
+        Cow::Owned(highlight_line(line, pos, &self.settings))
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str>
+    {
+        if !self.color
+        {
+            return Cow::Borrowed(hint);
+        }
+        Cow::Owned(format!("{}{}{}", DIM, hint, RESET))
+    }
+
+    // Every keystroke or cursor move can change the colors (a digit joining a number, the cursor reaching a paren)
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool
+    {
+        self.color
+    }
+}
+
+impl Validator for CalcHelper {}
+
+impl Helper for CalcHelper {}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn painted(style: &str, text: &str) -> String
+    {
+        format!("{}{}{}", style, text, RESET)
+    }
+
+    #[test]
+    fn highlighting_follows_the_settings()
+    {
+        let mut settings = Settings::default();
+        let line = "d1,234 + FFh # note";
+        let number = painted(PREFIX, "d") + &painted(DIGITS, "1");
+        let suffixed = painted(DIGITS, "FF") + &painted(PREFIX, "h");
+        // Without `:accept-commas` the comma separates, and `234` has no base
+        let expected = format!("{},{} {} {} {}", number, painted(ERROR, "234"), painted(OPERATOR, "+"), suffixed, painted(DIM, "# note"));
+        assert_eq!(highlight_line(line, 0, &settings), expected);
+
+        settings.accept_commas = true;
+        let number = painted(PREFIX, "d") + &painted(DIGITS, "1,234");
+        let expected = format!("{} {} {} {}", number, painted(OPERATOR, "+"), suffixed, painted(DIM, "# note"));
+        assert_eq!(highlight_line(line, 0, &settings), expected);
+
+        settings.disabled_ops = vec![Operation::Add];
+        let expected = format!("{} {} {} {}", number, painted(ERROR, "+"), suffixed, painted(DIM, "# note"));
+        assert_eq!(highlight_line(line, 0, &settings), expected);
+
+        // An argument's output base is taken as one
+        let expected = format!("{}({}{} {}, {}{})", "assert", painted(PREFIX, "d"), painted(DIGITS, "4"), painted(PREFIX, "d"), painted(PREFIX, "d"), painted(DIGITS, "4"));
+        assert_eq!(highlight_line("assert(d4 d, d4)", 0, &settings), expected);
+    }
+}
@@ -0,0 +1,21 @@
+//! Expression evaluation for the calculator: numbers in several bases, the tokenizer,
+//! shunting-yard conversion to RPN, evaluation and the session that holds variables.
+//! The interactive console in `main.rs` is a thin layer on top of `Session`.
+
+mod error;
+mod eval;
+mod expr;
+mod function;
+mod number;
+mod session;
+mod settings;
+mod token;
+
+pub use error::CalcError;
+pub use eval::{evaluate_rpn, evaluate_rpn_values, evaluate_rpn_with, evaluate_streaming, shunting_yard, Evaluation, FunctionCaller};
+pub use expr::{eval_ast, Expr};
+pub use function::{seed_random, Function};
+pub use number::{fits_width, fits_width_unsigned, format_value, format_with, Base, FormatOptions, Number};
+pub use session::{eval_with, EvalContext, EvalResult, EvalStats, Session, UserFunction, CONSTANTS, LAST_RESULT};
+pub use settings::{DivRound, ModStyle, NegativeShift, OverflowMode, Settings, DEFAULT_MAX_INPUT_LEN, WIDTHS};
+pub use token::{closing_bracket, is_base_prefix, is_digit_group, is_identifier, is_implicit_number, is_number_literal, operators, suffix_literal, tokenize, tokenize_lenient, tokenize_lenient_with, tokenize_lenient_with_base, tokenize_spans, tokenize_spans_lenient, tokenize_with, tokenize_with_base, Associativity, OpInfo, Operation, Spanned, Token};
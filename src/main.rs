@@ -1,319 +1,1696 @@
+mod helper;
+
+use better_dev_calc::{
+    fits_width, fits_width_unsigned, format_with, operators, seed_random, Associativity, Base, CalcError, DivRound, EvalResult, Expr, FormatOptions, ModStyle, NegativeShift, Number, Operation, OverflowMode, Session, Settings,
+    CONSTANTS, WIDTHS,
+};
+use helper::CalcHelper;
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, EditMode, Editor};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
 use std::process::Command;
+use std::time::{Duration, Instant};
+
+// Every `:` command with what it does, for the dispatcher in `Repl::run_command`, `:help` and tab completion.
+const COMMANDS: &[(&str, &str)] = &[
+    (":accept-commas", "on|off: read commas between groups of three decimal digits as part of the number"),
+    (":as", "dec|hex|bin|oct: show the last result in another base"),
+    (":base", "dec|hex|bin|oct|off: read unprefixed numbers in a base and show results in it"),
+    (":base-hint", "on|off: point out a result shown in a base none of its numbers were in"),
+    (":build", "on|off: add each line to one expression and show its value so far"),
+    (":build-clear", "start the expression being built over"),
+    (":bytes", "show the last result's bytes in big- and little-endian order"),
+    (":case", "upper|lower: the case of hex letters in results"),
+    (":clear", "[hard]: clear the console, and with hard its scrollback too"),
+    (":codes", "on|off: show error codes with errors"),
+    (":complement", "VALUE width 8|16|32|64: a value's bits beside its one's and two's complement"),
+    (":consts", "list the built-in constants"),
+    (":copy", "copy the last result to the clipboard"),
+    (":debug", "on|off: show each literal as it was read"),
+    (":div-round", "trunc|floor: how / rounds"),
+    (":echo-bases", "on|off: show results in every base"),
+    (":emit", "c|rust|python|off: show results as literals for pasting into code, like 0xFF"),
+    (":explain", "EXPRESSION: say in words what evaluating an expression does, step by step"),
+    (":funcs", "list the functions defined with def"),
+    (":group", "N|off: separate result digits into groups of N with _"),
+    (":group-bin", "N|off: group binary result digits by N instead, like 4 for nibbles"),
+    (":group-hex", "N|off: group hex result digits by N instead, like 2 for bytes"),
+    (":help", "list the commands"),
+    (":human", "on|si|off: show decimal results as byte sizes, in KiB (or kB with si)"),
+    (":mod-style", "trunc|floor: the sign of % remainders"),
+    (":neg-shift", "error|reverse: what a shift by a negative amount does"),
+    (":ops", "list the operators, tightest-binding first"),
+    (":overflow", "checked|wrap|saturate: what + - * / and negation do when a result doesn't fit"),
+    (":pad", "8|16|32|64|off: zero-pad results to a bit width"),
+    (":prefix", "on|off: write the base prefix in front of results"),
+    (":quit", "leave the calculator"),
+    (":regfmt", "8|16|32|64: register layout, padded, in groups of 4, uppercase"),
+    (":reset", "forget all variables, results and modes, after asking (:reset! doesn't ask)"),
+    (":sexpr", "EXPRESSION: show how an expression is grouped, like (+ d2 (* d3 d4))"),
+    (":simplify", "EXPRESSION: work out the parts that don't depend on a variable"),
+    (":stats", "on|off: show how many tokens and operations each result took"),
+    (":trace", "on|off: show the value of each parenthesized group"),
+    (":undo", "take back the last change to variables, functions or modes"),
+    (":unsigned", "on|off: read values as unsigned 64-bit, up to hFFFFFFFFFFFFFFFF"),
+    (":vars", "list the variables"),
+    (":width", "8|16|32|64: the bit width results are inspected at"),
+];
+
+// How many changes `:undo` can take back.
+const MAX_UNDO: usize = 32;
+
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
 
+// Colors are used on a terminal unless NO_COLOR (https://no-color.org) is set to anything.
+fn colors_enabled() -> bool
+{
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && std::io::stdout().is_terminal()
+}
 
-#[derive(Debug, PartialEq)]
-enum Operation 
+// Asks `question` at the console and waits for an answer. Only `y` (or `yes`) goes ahead.
+fn confirm(question: &str) -> bool
 {
-    Add,
-    Subtract,
-    Multiply,
-    OpenParen,
-    CloseParen,
+    print!("{} (y/N) ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
 }
 
-impl Operation 
+fn parse_switch(argument: &str, usage: &str) -> Result<bool, String>
 {
-    fn precedence(&self) -> u8
+    match argument
     {
-        match self 
-        {
-            Operation::Add | Operation::Subtract => 1,
-            Operation::Multiply => 2,
-            Operation::OpenParen | Operation::CloseParen => 0,
-        }
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Usage: {} on|off", usage)),
+    }
+}
+
+fn parse_width(argument: &str) -> Option<u32>
+{
+    argument.parse().ok().filter(|width| WIDTHS.contains(width))
+}
+
+// Reads a comma-separated list of operators, by symbol or word: `*,(,)` or `and,or`.
+fn parse_operators(list: &str) -> Result<Vec<Operation>, String>
+{
+    list.split(',')
+        .map(str::trim)
+        .filter(|symbol| !symbol.is_empty())
+        .map(|symbol| Operation::from_symbol(symbol).ok_or_else(|| format!("Unknown operator: {}", symbol)))
+        .collect()
+}
+
+fn switch_name(on: bool) -> &'static str
+{
+    if on { "on" } else { "off" }
+}
+
+// Units for `:human`: powers of 1024 (`KiB`) or, for SI, of 1000 (`kB`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteUnits
+{
+    Binary,
+    Si,
+}
+
+// A byte count in the largest unit it reaches, to one decimal place, like `1.5 MiB`. Below one kilobyte
+// the plain number already says it best, so there is none.
+fn human_size(bytes: i64, units: ByteUnits) -> Option<String>
+{
+    let (step, names) = match units
+    {
+        ByteUnits::Binary => (1024.0, ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+        ByteUnits::Si => (1000.0, ["kB", "MB", "GB", "TB", "PB", "EB"]),
+    };
+
+    let mut size = bytes as f64 / step;
+    if size < 1.0
+    {
+        return None;
+    }
+    let mut unit = 0;
+    // Compare what will be printed, so that 1023.96 KiB shows as 1.0 MiB rather than 1024.0 KiB
+    while unit + 1 < names.len() && (size * 10.0).round() / 10.0 >= step
+    {
+        size /= step;
+        unit += 1;
     }
+    Some(format!("{:.1} {}", size, names[unit]))
+}
+
+// Languages `:emit` writes results for, as literals that can be pasted into their source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit
+{
+    C,
+    // Like C, but `0o` for octal and digits grouped with `_`
+    Rust,
+    // `0o` for octal, and no grouping
+    Python,
+}
 
-    fn from_char(c: char) -> Result<Self, String> 
+impl Emit
+{
+    fn from_name(name: &str) -> Option<Self>
     {
-        match c 
+        match name
         {
-            '+' => Ok(Operation::Add),
-            '-' => Ok(Operation::Subtract),
-            '*' => Ok(Operation::Multiply),
-            '(' => Ok(Operation::OpenParen),
-            ')' => Ok(Operation::CloseParen),
-            _ => Err(format!("Invalid operator: {}", c)),
+            "c" => Some(Emit::C),
+            "rust" => Some(Emit::Rust),
+            "python" => Some(Emit::Python),
+            _ => None,
         }
     }
 
-    fn apply(&self, left: i64, right: i64) -> i64 
+    fn name(&self) -> &'static str
     {
-        match self 
+        match self
         {
-            Operation::Add => left + right,
-            Operation::Subtract => left - right,
-            Operation::Multiply => left * right,
-            _ => unreachable!(),
+            Emit::C => "c",
+            Emit::Rust => "rust",
+            Emit::Python => "python",
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum Base 
+// A value as a literal of `language`, like `0xFF`. Padding and letter case still follow `format`.
+fn emit_literal(num: &Number, language: Emit, format: &FormatOptions) -> String
 {
-    Decimal,
-    Hexadecimal,
+    let group = match (language, num.base)
+    {
+        (Emit::Rust, Base::Decimal) => Some(3),
+        (Emit::Rust, Base::Hexadecimal | Base::Binary) => Some(4),
+        _ => None,
+    };
+    let options = FormatOptions { group, hex_group: None, bin_group: None, prefix: false, ..*format };
+    let text = format_with(num.value, num.base, &options);
+    let (sign, digits) = match text.strip_prefix('-')
+    {
+        Some(digits) => ("-", digits),
+        None => ("", text.as_str()),
+    };
+    let prefix = match (num.base, language)
+    {
+        (Base::Decimal, _) => "",
+        (Base::Hexadecimal, _) => "0x",
+        (Base::Binary, _) => "0b",
+        // C has no `0o`: a leading zero is what makes a literal octal
+        (Base::Octal, Emit::C) => "0",
+        (Base::Octal, _) => "0o",
+    };
+    format!("{}{}{}", sign, prefix, digits)
 }
 
-impl Base 
+// `#` comments out the rest of the line, then `;` separates the expressions on it.
+// A `;` inside a comment is part of the comment, like in a shell.
+fn split_segments(line: &str) -> impl Iterator<Item = &str> 
 {
-    fn from_char(c: char) -> Result<Self, String> 
-    {
-        match c 
-        {
-            'd' => Ok(Base::Decimal),
-            'h' => Ok(Base::Hexadecimal),
-            _ => Err(format!("Invalid base: {}", c)),
-        }
+    let code = line.split_once('#').map_or(line, |(code, _)| code);
+    code.split(';').map(str::trim).filter(|segment| !segment.is_empty())
+}
+
+// `hard` also empties the scrollback, for a truly fresh screen: erase the display (`ESC[2J`) and the lines scrolled
+// off it (`ESC[3J`), then put the cursor back at the top.
+fn clear_console(hard: bool) {
+    if cfg!(target_os = "windows") {
+        // Windows
+        Command::new("cmd").args(["/C", "cls"]).status().unwrap();
+    } else {
+        // Unix
+        Command::new("clear").status().unwrap();
+    }
+    if hard {
+        print!("\x1b[2J\x1b[3J\x1b[H");
+        let _ = io::stdout().flush();
     }
 }
 
-#[derive(Debug)]
-struct Number 
+// Owns the system clipboard for the whole session: on X11 the copied text is
+// only served while the handle that set it is still alive.
+struct Clipboard
 {
-    value: i64,
-    base: Base,
+    #[cfg(feature = "clipboard")]
+    handle: Option<arboard::Clipboard>,
 }
 
-impl Number 
+impl Clipboard
 {
-    fn parse(input: &str) -> Result<Self, String> 
+    fn new() -> Self
     {
-        if input.is_empty() 
+        Clipboard
         {
-            return Err("Empty number".into());
+            #[cfg(feature = "clipboard")]
+            handle: None,
         }
+    }
 
-        let base = Base::from_char(input.chars().next().unwrap())?; // The first character is the base
-        let value_str = &input[1..]; // The rest is the value
-
-        let value = match base 
+    #[cfg(feature = "clipboard")]
+    fn copy(&mut self, text: &str) -> Result<(), String>
+    {
+        if self.handle.is_none()
         {
-            Base::Decimal => value_str
-                .parse()
-                .map_err(|_| format!("Invalid decimal number: {}", value_str))?,
-            Base::Hexadecimal => i64::from_str_radix(value_str, 16)
-                .map_err(|_| format!("Invalid hexadecimal number: {}", value_str))?,
-        };
+            let handle = arboard::Clipboard::new().map_err(|e| format!("Clipboard unavailable: {}", e))?;
+            self.handle = Some(handle);
+        }
 
-        Ok(Number { value, base })
+        self.handle
+            .as_mut()
+            .unwrap()
+            .set_text(text)
+            .map_err(|e| format!("Clipboard unavailable: {}", e))
     }
 
-    fn format(&self) -> String 
+    #[cfg(not(feature = "clipboard"))]
+    fn copy(&mut self, _text: &str) -> Result<(), String>
     {
-        match self.base 
-        {
-            Base::Decimal => format!("d{}", self.value),
-            Base::Hexadecimal => format!("h{:X}", self.value),
-        }
+        Err("Clipboard support was not compiled in (enable the `clipboard` feature)".into())
     }
 }
 
-#[derive(Debug)]
-enum Token 
+#[derive(Debug, Default)]
+struct Options
+{
+    quiet: bool,
+    // Evaluated in order before the console starts, each as if it had been typed in
+    expressions: Vec<String>,
+    // Stay in the console after the `-e` expressions instead of exiting
+    interactive: bool,
+    // Base of numbers written without a prefix
+    radix: Option<Base>,
+    max_input_len: Option<usize>,
+    // A file or fifo whose lines are evaluated as they arrive
+    follow: Option<String>,
+    timeout: Option<Duration>,
+    // Keep typed lines out of the up-arrow history
+    no_history: bool,
+    // Don't ask before `:reset`
+    force: bool,
+    // Results without their base prefix, as `FF`
+    no_prefix: bool,
+    unsigned: bool,
+    // `None` is rustyline's default, emacs
+    edit_mode: Option<EditMode>,
+    // Makes `rand` repeatable
+    seed: Option<u64>,
+    // A file (or `-` for standard input) whose failing lines are listed
+    lint: Option<String>,
+    // An expression to time over this many evaluations
+    repeat: Option<(u32, String)>,
+    // Expressions whose tokens are printed as JSON instead of being evaluated
+    dump_tokens: Vec<String>,
+    // Replaces BETTERDEVCALC_DISABLE_OPS
+    disabled_ops: Option<Vec<Operation>>,
+    // Written around each result when it isn't going to a person at the console, like `RESULT=` before `hFF`
+    result_prefix: String,
+    result_suffix: String,
+}
+
+// Reads `500ms` or `2s`.
+fn parse_duration(text: &str) -> Option<Duration>
 {
-    Number(Number),
-    Operation(Operation),
+    if let Some(millis) = text.strip_suffix("ms")
+    {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    text.strip_suffix('s')?.parse().ok().map(Duration::from_secs)
 }
 
-fn tokenize(expr: &str) -> Result<Vec<Token>, String> 
+fn parse_edit_mode(name: &str) -> Option<EditMode>
 {
-    let mut tokens = Vec::new();
-    let mut current_number = String::new();
+    match name
+    {
+        "emacs" => Some(EditMode::Emacs),
+        "vi" => Some(EditMode::Vi),
+        _ => None,
+    }
+}
 
-    let chars: Vec<char> = expr.chars().filter(|c| !c.is_whitespace()).collect();
-    let mut i = 0;
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String>
+{
+    let mut options = Options
+    {
+        no_history: std::env::var_os("BETTERDEVCALC_NO_HISTORY").is_some_and(|value| !value.is_empty()),
+        ..Options::default()
+    };
+    if let Some(mode) = std::env::var("BETTERDEVCALC_EDIT_MODE").ok().filter(|value| !value.is_empty())
+    {
+        match parse_edit_mode(&mode)
+        {
+            Some(mode) => options.edit_mode = Some(mode),
+            None => eprintln!("Warning: ignoring BETTERDEVCALC_EDIT_MODE={} (use emacs or vi)", mode),
+        }
+    }
 
-    while i < chars.len() 
+    while let Some(arg) = args.next()
     {
-        match chars[i] {
-            'd' | 'h' => 
+        match arg.as_str()
+        {
+            "--quiet" => options.quiet = true,
+            "-e" => options.expressions.push(args.next().ok_or("Missing expression after -e")?),
+            "-i" => options.interactive = true,
+            "--no-history" => options.no_history = true,
+            "--force" | "-y" => options.force = true,
+            "--no-prefix" => options.no_prefix = true,
+            "--unsigned" => options.unsigned = true,
+            "--edit-mode" =>
             {
-                if !current_number.is_empty() 
-                {
-                    tokens.push(Token::Number(Number::parse(&current_number)?));
-                    current_number.clear();
-                }
-                current_number.push(chars[i]);
+                let mode = args.next().ok_or("Missing mode after --edit-mode")?;
+                options.edit_mode = Some(parse_edit_mode(&mode).ok_or_else(|| format!("Unsupported edit mode: {} (use emacs or vi)", mode))?);
             }
-            c if c.is_digit(16) => 
+            "--radix" =>
             {
-                current_number.push(c);
+                let radix = args.next().ok_or("Missing radix after --radix")?;
+                let base = radix.parse().ok().and_then(Base::from_radix);
+                options.radix = Some(base.ok_or_else(|| format!("Unsupported radix: {} (use 2, 8, 10 or 16)", radix))?);
             }
-            c @ ('+' | '-' | '*' | '(' | ')') => 
+            // The usual choice of radix, for working mostly in hex
+            "--hex" => options.radix = Some(Base::Hexadecimal),
+            "--dump-tokens" => options.dump_tokens.push(args.next().ok_or("Missing expression after --dump-tokens")?),
+            "--disable-ops" =>
             {
-                if !current_number.is_empty() 
-                {
-                    tokens.push(Token::Number(Number::parse(&current_number)?));
-                    current_number.clear();
-                }
-                tokens.push(Token::Operation(Operation::from_char(c)?));
+                let list = args.next().ok_or("Missing operators after --disable-ops")?;
+                options.disabled_ops = Some(parse_operators(&list)?);
+            }
+            "--repeat" =>
+            {
+                let count = args.next().ok_or("Missing count after --repeat")?;
+                let count = count.parse().ok().filter(|count| *count > 0).ok_or_else(|| format!("Invalid repeat count: {}", count))?;
+                options.repeat = Some((count, args.next().ok_or("Missing expression after --repeat")?));
+            }
+            "--follow" => options.follow = Some(args.next().ok_or("Missing path after --follow")?),
+            "--lint" => options.lint = Some(args.next().ok_or("Missing path after --lint")?),
+            "--result-prefix" => options.result_prefix = args.next().ok_or("Missing text after --result-prefix")?,
+            "--result-suffix" => options.result_suffix = args.next().ok_or("Missing text after --result-suffix")?,
+            "--timeout" =>
+            {
+                let timeout = args.next().ok_or("Missing duration after --timeout")?;
+                options.timeout = Some(parse_duration(&timeout).ok_or_else(|| format!("Invalid duration: {} (use e.g. 500ms or 2s)", timeout))?);
+            }
+            "--seed" =>
+            {
+                let seed = args.next().ok_or("Missing number after --seed")?;
+                options.seed = Some(seed.parse().map_err(|_| format!("Invalid seed: {}", seed))?);
             }
-            _ => return Err(format!("Invalid character: {}", chars[i])),
+            "--max-input" =>
+            {
+                let bytes = args.next().ok_or("Missing byte count after --max-input")?;
+                options.max_input_len = Some(bytes.parse().map_err(|_| format!("Invalid byte count: {}", bytes))?);
+            }
+            _ => return Err(format!("Unknown argument: {}", arg)),
         }
-        i += 1;
     }
 
-    if !current_number.is_empty() 
-    {
-        tokens.push(Token::Number(Number::parse(&current_number)?));
-    }
+    Ok(options)
+}
+
+// The value's bytes at `width` bits, most significant first, as hex pairs.
+fn hex_bytes(value: i64, width: u32) -> Vec<String>
+{
+    let bytes = value.to_be_bytes();
+    bytes[bytes.len() - width as usize / 8..].iter().map(|byte| format!("{:02X}", byte)).collect()
+}
 
-    Ok(tokens)
+// The bits of `value` at `width`, then of its one's complement (every bit flipped) and its two's complement
+// (flipped, plus one), each with the decimal the bits stand for, lined up under one another.
+fn complements(value: i64, width: u32, unsigned: bool) -> String
+{
+    let shift = 64 - width;
+    let bits = FormatOptions { pad_width: Some(width), unsigned: true, ..FormatOptions::default() };
+    let decimal = FormatOptions { unsigned, ..FormatOptions::default() };
+    // The bits read back at `width`, with the sign bit extended unless values are unsigned
+    let reading = |pattern: u64| if unsigned { pattern as i64 } else { ((pattern << shift) as i64) >> shift };
+
+    let pattern = value as u64 & (u64::MAX >> shift);
+    let ones = !pattern & (u64::MAX >> shift);
+    let twos = ones.wrapping_add(1) & (u64::MAX >> shift);
+    let rows = [("value", pattern, value), ("one's complement", ones, reading(ones)), ("two's complement", twos, reading(twos))];
+    let lines: Vec<String> = rows
+        .iter()
+        .map(|(label, pattern, value)| format!("{:16}  {}  {}", label, format_with(*pattern as i64, Base::Binary, &bits), format_with(*value, Base::Decimal, &decimal)))
+        .collect();
+    lines.join("\n")
 }
 
-// Converts infix tokens to Reverse Polish Notation using the shunting yard algorithm.
-fn shunting_yard(tokens: Vec<Token>) -> Result<Vec<Token>, String> 
+// Evaluates each line of `path` as it arrives, until the end of the file. A fifo is reopened whenever its
+// writer goes away, so one producer after another can connect. Returns false if anything failed.
+fn follow(repl: &mut Repl, path: &str) -> bool
 {
-    let mut output = Vec::new();
-    let mut operator_stack = Vec::new();
+    let mut ok = true;
 
-    for token in tokens 
+    loop
     {
-        match token 
+        let file = match File::open(path)
         {
-            Token::Number(_) => output.push(token),
-            Token::Operation(Operation::OpenParen) => operator_stack.push(token),
-            Token::Operation(Operation::CloseParen) => 
+            Ok(file) => file,
+            Err(err) =>
             {
-                while let Some(Token::Operation(op)) = operator_stack.last() 
-                {
-                    if *op == Operation::OpenParen 
-                    {
-                        operator_stack.pop();
-                        break;
-                    }
-                    output.push(operator_stack.pop().unwrap());
-                }
+                eprintln!("Error: Cannot open {}: {}", path, err);
+                return false;
             }
-            Token::Operation(op) => 
+        };
+        let is_fifo = is_fifo(&file);
+
+        for line in BufReader::new(file).lines()
+        {
+            match line
             {
-                while let Some(Token::Operation(top_op)) = operator_stack.last() 
+                Ok(line) => ok &= line.trim().is_empty() || repl.run_line(&line),
+                Err(err) =>
                 {
-                    if *top_op == Operation::OpenParen || top_op.precedence() < op.precedence() 
-                    {
-                        break;
-                    }
-                    output.push(operator_stack.pop().unwrap());
+                    eprintln!("Error: Cannot read {}: {}", path, err);
+                    return false;
                 }
-                operator_stack.push(Token::Operation(op));
             }
+            if repl.quit
+            {
+                return ok;
+            }
+        }
+
+        if !is_fifo
+        {
+            return ok;
+        }
+    }
+}
+
+// Per-shell defaults, like from an `.envrc`. They sit between the built-in defaults and anything typed in
+// the session, so `:reset` goes back to them. A value that can't be used is reported and skipped.
+fn apply_environment(settings: &mut Settings)
+{
+    if let Some(width) = std::env::var("BETTERDEVCALC_WIDTH").ok().filter(|value| !value.is_empty())
+    {
+        match parse_width(&width)
+        {
+            Some(width) => settings.width = width,
+            None => eprintln!("Warning: ignoring BETTERDEVCALC_WIDTH={} (use 8, 16, 32 or 64)", width),
+        }
+    }
+    if let Some(base) = std::env::var("BETTERDEVCALC_BASE").ok().filter(|value| !value.is_empty())
+    {
+        match Base::from_name(&base)
+        {
+            Some(base) => settings.output_base = Some(base),
+            None => eprintln!("Warning: ignoring BETTERDEVCALC_BASE={} (use dec, hex, bin or oct)", base),
+        }
+    }
+    if let Ok(list) = std::env::var("BETTERDEVCALC_DISABLE_OPS")
+    {
+        match parse_operators(&list)
+        {
+            Ok(ops) => settings.disabled_ops = ops,
+            Err(err) => eprintln!("Warning: ignoring BETTERDEVCALC_DISABLE_OPS={} ({})", list, err),
         }
     }
+}
 
-    while let Some(op) = operator_stack.pop() 
+// Evaluates `expression` `count` times, prints its result once and then how long the evaluations took,
+// for a quick look at the speed of one kind of expression. Stops at the first error.
+fn repeat(repl: &mut Repl, count: u32, expression: &str) -> bool
+{
+    let mut output = String::new();
+    let start = Instant::now();
+    for _ in 0..count
     {
-        match op 
+        match repl.eval_segment(expression)
         {
-            Token::Operation(Operation::OpenParen) => 
+            Ok(result) => output = result,
+            Err(err) =>
             {
-                return Err("Unmatched open parenthesis".into());
+                repl.report(&err);
+                return false;
             }
-            _ => output.push(op),
         }
     }
+    let elapsed = start.elapsed();
 
-    Ok(output)
+    println!("{}", output);
+    eprintln!("{} evaluations in {:?}, {:?} each", count, elapsed, elapsed / count);
+    true
 }
 
-// Evaluates the expression in Reverse Polish Notation.
-fn evaluate_rpn(tokens: Vec<Token>) -> Result<i64, String> 
+// Evaluates every line of a file (or of standard input, for `-`) without printing any result, and lists
+// the lines that fail as `path:line: error`, for checking a corpus of expressions in CI.
+fn lint(repl: &mut Repl, path: &str) -> bool
 {
-    let mut stack = Vec::new();
+    let reader: Box<dyn BufRead> = if path == "-"
+    {
+        Box::new(io::stdin().lock())
+    }
+    else
+    {
+        match File::open(path)
+        {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) =>
+            {
+                eprintln!("Error: Cannot open {}: {}", path, err);
+                return false;
+            }
+        }
+    };
+    let mut ok = true;
 
-    for token in tokens 
+    for (index, line) in reader.lines().enumerate()
     {
-        match token 
+        let line = match line
         {
-            Token::Number(num) => stack.push(num.value),
-            Token::Operation(op) => 
+            Ok(line) => line,
+            Err(err) =>
             {
-                let right = stack.pop().ok_or("Invalid expression")?;
-                let left = stack.pop().ok_or("Invalid expression")?;
-                stack.push(op.apply(left, right));
+                eprintln!("Error: Cannot read {}: {}", path, err);
+                return false;
             }
+        };
+
+        // Commands still apply, so a corpus can set the modes its expressions are meant for
+        let limit = repl.session.settings().max_input_len;
+        let errors: Vec<String> = if line.len() > limit
+        {
+            vec![repl.error_text(&CalcError::InputTooLong(limit))]
+        }
+        else if line.trim().starts_with(':')
+        {
+            repl.run_command(line.trim()).err().map(|err| format!("Error: {}", err)).into_iter().collect()
+        }
+        else
+        {
+            let failures: Vec<CalcError> = split_segments(&line).filter_map(|segment| repl.eval_segment(segment).err()).collect();
+            failures.iter().map(|err| repl.error_text(err)).collect()
+        };
+        for err in errors
+        {
+            println!("{}:{}: {}", path, index + 1, err);
+            ok = false;
+        }
+
+        if repl.quit
+        {
+            break;
         }
     }
 
-    stack.pop().ok_or("Invalid expression".into())
+    ok
+}
+
+#[cfg(unix)]
+fn is_fifo(file: &File) -> bool
+{
+    use std::os::unix::fs::FileTypeExt;
+    file.metadata().is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_file: &File) -> bool
+{
+    false
+}
+
+// How results are shown. Unlike the session's settings these never change a value, only its display.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Presentation
+{
+    // Off: only the result itself is printed
+    echo_bases: bool,
+    // Off: the literals the tokenizer read aren't echoed
+    debug: bool,
+    // Off: errors are shown without their `CalcError::code`
+    codes: bool,
+    // Off: the work an evaluation took isn't shown
+    stats: bool,
+    // Off: parenthesized groups aren't shown with their values
+    trace: bool,
+    // Off: nothing is said when the output base differs from every literal's
+    base_hint: bool,
+    // Off: decimal results aren't shown as byte sizes
+    human: Option<ByteUnits>,
+    // Plain numbers, as in `hFF`
+    format: FormatOptions,
+    // Results in this calculator's own syntax
+    emit: Option<Emit>,
+}
+
+// The console around an evaluation session.
+struct Repl
+{
+    // Set by `:quit`, for whatever is feeding lines in to stop
+    quit: bool,
+    session: Session,
+    // What the command line set up, which `:reset` goes back to
+    defaults: Settings,
+    presentation_defaults: Presentation,
+    presentation: Presentation,
+    // The state before each of the latest changes, newest last, for `:undo`
+    undo: VecDeque<(Session, Presentation)>,
+    clipboard: Clipboard,
+    color: bool,
+    // `--quiet`: errors are just their `Error:` line, without the caret diagnostic
+    quiet: bool,
+    // From `--result-prefix` and `--result-suffix`, and empty at the console
+    result_prefix: String,
+    result_suffix: String,
+    // Ask before `:reset` throws the session away. Only someone at the console is asked, and not with `--force`.
+    confirm: bool,
+    // `:build`: each line goes on the end of one expression instead of standing on its own
+    build: bool,
 }
 
-fn process_expression(input: &str) -> Result<String, String> 
+impl Repl
 {
-    let input = input.trim();
-    if input.is_empty() 
+    // Formats a result for printing, with the presentation options applied.
+    fn render(&self, result: &EvalResult) -> String
     {
-        return Err("Empty expression".into());
+        let mut out = String::new();
+
+        // Each literal in the base it was read in, to confirm how grouping and prefixes were understood
+        if self.presentation.debug && !result.literals.is_empty()
+        {
+            let literals: Vec<String> = result.literals.iter().map(Number::to_string).collect();
+            out.push_str(&self.dim(&format!("literals: {}", literals.join(" "))));
+            out.push('\n');
+        }
+
+        out.push_str(&self.result_prefix);
+        out.push_str(&result.display_using(|num| self.shown(num)));
+        out.push_str(&self.result_suffix);
+
+        if let Some(units) = self.presentation.human.filter(|_| result.parts.is_empty() && result.number.base == Base::Decimal)
+        {
+            if let Some(size) = human_size(result.number.value, units)
+            {
+                out.push_str(&format!(" ({})", size));
+            }
+        }
+
+        // A footer per value would be hard to tell apart, so results with several get none
+        if self.presentation.echo_bases && result.parts.is_empty()
+        {
+            let others: Vec<String> = Base::ALL
+                .iter()
+                .filter(|base| **base != result.number.base)
+                .map(|base| format_with(result.number.value, *base, &self.format_options()))
+                .collect();
+            out.push_str("  ");
+            out.push_str(&self.dim(&format!("({})", others.join(" "))));
+        }
+
+        // `hFF + hFF d` is easy to type meaning `h`, so say so when no literal was in the output base
+        if self.presentation.base_hint && !result.literals.is_empty() && result.literals.iter().all(|num| num.base != result.number.base)
+        {
+            let mut bases: Vec<&str> = Vec::new();
+            for num in &result.literals
+            {
+                if !bases.contains(&num.base.name())
+                {
+                    bases.push(num.base.name());
+                }
+            }
+            out.push('\n');
+            out.push_str(&self.dim(&format!("note: result shown in {}; inputs were {}", result.number.base.name(), bases.join(" and "))));
+        }
+
+        if self.presentation.stats
+        {
+            out.push('\n');
+            out.push_str(&self.dim(&format!("tokens: {}, operations: {}", result.stats.tokens, result.stats.operations)));
+        }
+
+        out
     }
 
-    let output_base = Base::from_char(input.chars().last().ok_or("Empty expression")?)?;
-    let expr = &input[..input.len() - 1].trim();
+    // How results are written: the presentation's layout, read unsigned when the session is
+    fn format_options(&self) -> FormatOptions
+    {
+        FormatOptions { unsigned: self.session.settings().unsigned, ..self.presentation.format }
+    }
 
-    let tokens = tokenize(expr)?;
-    
-    let rpn_tokens = shunting_yard(tokens)?;
-    
-    let result = evaluate_rpn(rpn_tokens)?;
+    fn format(&self, num: &Number) -> String
+    {
+        format_with(num.value, num.base, &self.format_options())
+    }
 
-    Ok(Number 
+    // A number as results print it, `:emit` included, so `:copy` takes exactly what was shown.
+    fn shown(&self, num: &Number) -> String
+    {
+        match self.presentation.emit
         {
-            value: result,
-            base: output_base,
-        }.format()
-    )
+            Some(language) => emit_literal(num, language, &self.format_options()),
+            None => self.format(num),
+        }
+    }
 
-}
+    fn describe_format(&self) -> String
+    {
+        let format = &self.presentation.format;
+        let pad = format.pad_width.map_or("off".to_string(), |width| format!("{} bits", width));
+        let group = format.group.map_or("off".to_string(), |size| size.to_string());
+        let hex_group = format.hex_group.map_or("same".to_string(), |size| size.to_string());
+        let bin_group = format.bin_group.map_or("same".to_string(), |size| size.to_string());
+        let case = if format.uppercase { "upper" } else { "lower" };
+        let negative = if format.twos_complement { ", negative values: two's complement" } else { "" };
+        format!("Padding: {}, groups: {}, hex groups: {}, binary groups: {}, case: {}{}", pad, group, hex_group, bin_group, case, negative)
+    }
 
-fn clear_console() {
-    if cfg!(target_os = "windows") {
-        // Windows
-        Command::new("cmd").args(&["/C", "cls"]).status().unwrap();
-    } else {
-        // Unix
-        Command::new("clear").status().unwrap();
+    fn report(&self, err: &CalcError)
+    {
+        eprintln!("{}", self.error_text(err));
     }
-}
 
-fn main() -> rustyline::Result<()> 
-{
-    let mut rl = DefaultEditor::new()?;
+    fn error_text(&self, err: &CalcError) -> String
+    {
+        if self.presentation.codes
+        {
+            format!("Error[{}]: {}", err.code(), err)
+        }
+        else
+        {
+            format!("Error: {}", err)
+        }
+    }
 
-    loop 
+    fn dim(&self, text: &str) -> String
     {
-        match rl.readline("Enter an expression (or 'q' to quit): ") 
+        if self.color
         {
-            Ok(line) => 
+            format!("{}{}{}", DIM, text, RESET)
+        }
+        else
+        {
+            text.to_string()
+        }
+    }
+
+    // Like `execute_line`, remembering the state from before the line if it changed anything `:undo` can put back:
+    // variables, functions, modes or presentation options. A plain calculation only changes the last result.
+    fn run_line(&mut self, line: &str) -> bool
+    {
+        // The whole line, before it is split into segments or traced, so an oversized paste is turned away untouched
+        let limit = self.session.settings().max_input_len;
+        if line.len() > limit
+        {
+            self.report(&CalcError::InputTooLong(limit));
+            return false;
+        }
+        if line.trim() == ":undo"
+        {
+            return self.execute_line(line);
+        }
+
+        let before = (self.session.clone(), self.presentation.clone());
+        let ok = self.execute_line(line);
+        let (session, presentation) = &before;
+        let changed = self.session.variables() != session.variables()
+            || self.session.functions() != session.functions()
+            || self.session.settings() != session.settings()
+            || self.presentation != *presentation;
+        if changed
+        {
+            if self.undo.len() == MAX_UNDO
             {
-                if line.is_empty() 
+                self.undo.pop_front();
+            }
+            self.undo.push_back(before);
+        }
+        ok
+    }
+
+    // Runs a command or evaluates every expression on a line, printing what comes out.
+    // Returns false if anything on the line failed.
+    fn execute_line(&mut self, line: &str) -> bool
+    {
+        // Lines from a file written on Windows can still carry the `\r` of a `\r\n` ending
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().starts_with(':') 
+        {
+            return match self.run_command(line.trim()) 
+            {
+                Ok(message) =>
                 {
-                    continue;
+                    if !message.is_empty()
+                    {
+                        println!("{}", message);
+                    }
+                    true
                 }
-                if line.trim() == "q" 
+                Err(err) =>
                 {
-                    break;
+                    eprintln!("Error: {}", err);
+                    false
                 }
-                if line.trim() == "c" 
+            };
+        }
+
+        if self.build
+        {
+            return match self.session.build(line)
+            {
+                Ok(Some(result)) =>
                 {
-                    clear_console();
-                    continue; 
+                    println!("{}", self.render(&result));
+                    true
                 }
-                rl.add_history_entry(&line)?;
-
-                match process_expression(&line) 
+                Ok(None) =>
                 {
-                    Ok(result) => println!("{}", result),
-                    Err(err) => eprintln!("Error: {}", err),
+                    println!("{}", self.dim(&format!("{} ...", self.session.built())));
+                    true
                 }
-            }
-            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
-            Err(err) => 
+                Err(err) =>
+                {
+                    self.report(&err);
+                    false
+                }
+            };
+        }
+
+        let mut ok = true;
+        for segment in split_segments(line) 
+        {
+            match self.eval_segment(segment) 
             {
-                eprintln!("Error: {}", err);
-                break;
+                Ok(output) => println!("{}", output),
+                Err(err) =>
+                {
+                    self.report(&err);
+                    // Like rustc: the expression again, with a caret under where it went wrong
+                    if let Some(offset) = self.session.error_offset(segment, &err).filter(|_| !self.quiet)
+                    {
+                        eprintln!("  {}", segment);
+                        eprintln!("  {}^", " ".repeat(segment[..offset].chars().count()));
+                    }
+                    ok = false;
+                }
             }
         }
+        ok
+    }
+
+    // Evaluates one expression, assignment or `def`, and returns what to print for it.
+    fn eval_segment(&mut self, segment: &str) -> Result<String, CalcError>
+    {
+        if segment.starts_with("def ")
+        {
+            return self.session.define_function(segment).map(|signature| format!("Defined {}", signature));
+        }
+        if segment.starts_with("table(")
+        {
+            let (name, rows) = self.session.table(segment)?;
+            let lines: Vec<String> = rows.iter().map(|(x, value)| format!("{}={} -> {}", name, self.format(x), self.format(value))).collect();
+            return Ok(lines.join("\n"));
+        }
+        // Worked out first, since an assignment can change the variables the groups use
+        let groups = if self.presentation.trace { self.session.groups(segment).unwrap_or_default() } else { Vec::new() };
+        let result = self.session.eval(segment)?;
+
+        let mut out = String::new();
+        for (text, value) in groups
+        {
+            out.push_str(&self.dim(&format!("{} = {}", text, format_with(value, result.number.base, &self.format_options()))));
+            out.push('\n');
+        }
+        out.push_str(&self.render(&result));
+        Ok(out)
+    }
+
+    // Runs a `:name [argument]` command and returns the message to print.
+    fn run_command(&mut self, line: &str) -> Result<String, String>
+    {
+        let (name, argument) = match line.split_once(char::is_whitespace)
+        {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (line, ""),
+        };
+
+        match name
+        {
+            ":quit" =>
+            {
+                self.quit = true;
+                Ok(String::new())
+            }
+            ":clear" =>
+            {
+                match argument
+                {
+                    "" => clear_console(false),
+                    "hard" => clear_console(true),
+                    _ => return Err("Usage: :clear [hard]".into()),
+                }
+                Ok(String::new())
+            }
+            ":help" =>
+            {
+                let width = COMMANDS.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+                let mut lines: Vec<String> = COMMANDS.iter().map(|(name, help)| format!("{:width$}  {}", name, help, width = width)).collect();
+                lines.push(String::new());
+                lines.push("Up/Down step through earlier lines, Ctrl-R searches them and Tab completes names".into());
+                Ok(lines.join("\n"))
+            }
+            ":reset" | ":reset!" =>
+            {
+                if name == ":reset" && self.confirm && !confirm("Are you sure?")
+                {
+                    return Ok("Session kept".into());
+                }
+                self.session.reset();
+                *self.session.settings_mut() = self.defaults.clone();
+                self.presentation = self.presentation_defaults.clone();
+                self.build = false;
+                Ok("Session reset".into())
+            }
+            ":undo" =>
+            {
+                let (session, presentation) = self.undo.pop_back().ok_or("Nothing to undo")?;
+                self.session = session;
+                self.presentation = presentation;
+                Ok("Undid the last change".into())
+            }
+            ":copy" =>
+            {
+                let result = self.session.last_result().map(|num| self.shown(num)).ok_or("No result to copy")?;
+                self.clipboard.copy(&result)?;
+                Ok(format!("Copied {}", result))
+            }
+            ":consts" =>
+            {
+                let lines: Vec<String> = CONSTANTS.iter().map(|(name, value)| format!("{} = {}", name, self.format(&Number { value: *value, base: Base::Decimal }))).collect();
+                Ok(lines.join("\n"))
+            }
+            ":sexpr" =>
+            {
+                if argument.is_empty()
+                {
+                    return Err("Usage: :sexpr EXPRESSION".into());
+                }
+                self.session.sexpr(argument).map_err(|err| err.to_string())
+            }
+            ":explain" =>
+            {
+                if argument.is_empty()
+                {
+                    return Err("Usage: :explain EXPRESSION".into());
+                }
+                self.session.explain(argument).map_err(|err| err.to_string())
+            }
+            ":simplify" =>
+            {
+                if argument.is_empty()
+                {
+                    return Err("Usage: :simplify EXPRESSION".into());
+                }
+                match &self.session.simplify(argument).map_err(|err| err.to_string())?
+                {
+                    Expr::Num(num) => Ok(format!("{}  (the whole expression is constant)", self.format(num))),
+                    expr => Ok(expr.to_sexpr()),
+                }
+            }
+            ":ops" =>
+            {
+                let disabled = &self.session.settings().disabled_ops;
+                let lines: Vec<String> = operators()
+                    .iter()
+                    .map(|info|
+                    {
+                        let kind = match info.arity
+                        {
+                            1 => "prefix",
+                            3 => "ternary",
+                            _ => "binary",
+                        };
+                        let associativity = if info.associativity == Associativity::Left { "left" } else { "right" };
+                        let line = format!("{:3} {:4} precedence {}, {}, {}-associative", info.symbol, info.word.unwrap_or(""), info.precedence, kind, associativity);
+                        if disabled.iter().any(|op| op.symbol() == info.symbol) { format!("{} (disabled)", line) } else { line }
+                    })
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            ":funcs" =>
+            {
+                let functions = self.session.functions();
+                if functions.is_empty()
+                {
+                    return Ok("No functions defined".into());
+                }
+                let lines: Vec<String> = functions.iter().map(|(name, function)| format!("def {}({}) = {}", name, function.params.join(", "), function.body)).collect();
+                Ok(lines.join("\n"))
+            }
+            ":vars" =>
+            {
+                let variables = self.session.variables();
+                if variables.is_empty()
+                {
+                    return Ok("No variables defined".into());
+                }
+                let lines: Vec<String> = variables.iter().map(|(name, num)| format!("{} = {}", name, self.format(num))).collect();
+                Ok(lines.join("\n"))
+            }
+            ":base" =>
+            {
+                // Explicit prefixes and output bases still win, so `d10` stays decimal ten
+                match argument
+                {
+                    "" => {}
+                    "off" =>
+                    {
+                        let settings = self.session.settings_mut();
+                        settings.implicit_base = None;
+                        settings.output_base = None;
+                    }
+                    _ =>
+                    {
+                        let base = Base::from_name(argument).ok_or("Usage: :base dec|hex|bin|oct|off")?;
+                        let settings = self.session.settings_mut();
+                        settings.implicit_base = Some(base);
+                        settings.output_base = Some(base);
+                    }
+                }
+                let settings = self.session.settings();
+                let input = settings.implicit_base.map_or("prefix required", |base| base.name());
+                let output = settings.output_base.map_or("inferred", |base| base.name());
+                Ok(format!("Input base: {}, output base: {}", input, output))
+            }
+            ":mod-style" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.session.settings_mut().mod_style = ModStyle::from_name(argument).ok_or("Usage: :mod-style trunc|floor")?;
+                }
+                Ok(format!("Modulo style: {}", self.session.settings().mod_style.name()))
+            }
+            ":div-round" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.session.settings_mut().div_round = DivRound::from_name(argument).ok_or("Usage: :div-round trunc|floor")?;
+                }
+                Ok(format!("Division rounding: {}", self.session.settings().div_round.name()))
+            }
+            ":neg-shift" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.session.settings_mut().negative_shift = NegativeShift::from_name(argument).ok_or("Usage: :neg-shift error|reverse")?;
+                }
+                Ok(format!("Negative shifts: {}", self.session.settings().negative_shift.name()))
+            }
+            ":overflow" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.session.settings_mut().overflow = OverflowMode::from_name(argument).ok_or("Usage: :overflow checked|wrap|saturate")?;
+                }
+                Ok(format!("Overflow: {}", self.session.settings().overflow.name()))
+            }
+            ":accept-commas" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.session.settings_mut().accept_commas = parse_switch(argument, ":accept-commas")?;
+                }
+                Ok(format!("Accept commas: {}", switch_name(self.session.settings().accept_commas)))
+            }
+            ":unsigned" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.session.settings_mut().unsigned = parse_switch(argument, ":unsigned")?;
+                }
+                Ok(format!("Unsigned: {}", switch_name(self.session.settings().unsigned)))
+            }
+            ":echo-bases" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.echo_bases = parse_switch(argument, ":echo-bases")?;
+                }
+                Ok(format!("Echo bases: {}", switch_name(self.presentation.echo_bases)))
+            }
+            ":human" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "si" => self.presentation.human = Some(ByteUnits::Si),
+                    _ => self.presentation.human = parse_switch(argument, ":human").map_err(|_| "Usage: :human on|si|off")?.then_some(ByteUnits::Binary),
+                }
+                let state = match self.presentation.human
+                {
+                    None => "off",
+                    Some(ByteUnits::Binary) => "on",
+                    Some(ByteUnits::Si) => "si",
+                };
+                Ok(format!("Byte sizes: {}", state))
+            }
+            ":emit" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "off" => self.presentation.emit = None,
+                    _ => self.presentation.emit = Some(Emit::from_name(argument).ok_or("Usage: :emit c|rust|python|off")?),
+                }
+                Ok(format!("Emit: {}", self.presentation.emit.map_or("off", |language| language.name())))
+            }
+            ":debug" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.debug = parse_switch(argument, ":debug")?;
+                }
+                Ok(format!("Debug: {}", switch_name(self.presentation.debug)))
+            }
+            ":as" =>
+            {
+                let base = Base::from_name(argument).ok_or("Usage: :as dec|hex|bin|oct")?;
+                let result = self.session.last_result().ok_or("No result to show")?;
+                Ok(format_with(result.value, base, &self.format_options()))
+            }
+            ":width" =>
+            {
+                if !argument.is_empty()
+                {
+                    let width = parse_width(argument).ok_or("Usage: :width 8|16|32|64")?;
+                    self.session.settings_mut().width = width;
+                }
+                Ok(format!("Width: {} bits", self.session.settings().width))
+            }
+            ":complement" =>
+            {
+                let usage = "Usage: :complement VALUE width 8|16|32|64";
+                let (value, width) = argument.rsplit_once(" width ").ok_or(usage)?;
+                let width = parse_width(width.trim()).ok_or(usage)?;
+                let value = match &self.session.simplify(value).map_err(|err| err.to_string())?
+                {
+                    Expr::Num(num) => num.value,
+                    Expr::Var(name) => self.session.variable(name).ok_or_else(|| CalcError::UndefinedVariable(name.clone()).to_string())?.value,
+                    _ => return Err(usage.into()),
+                };
+                if !fits_width(value, width)
+                {
+                    return Err(format!("{} doesn't fit in {} bits", self.format(&Number { value, base: Base::Decimal }), width));
+                }
+                Ok(complements(value, width, self.session.settings().unsigned))
+            }
+            ":bytes" =>
+            {
+                let result = self.session.last_result().ok_or("No result to show")?;
+                let settings = self.session.settings();
+                let width = settings.width;
+                let fits = if settings.unsigned { fits_width_unsigned(result.value, width) } else { fits_width(result.value, width) };
+                if !fits
+                {
+                    return Err(format!("{} doesn't fit in {} bits", self.format(result), width));
+                }
+                let mut bytes = hex_bytes(result.value, width);
+                let big_endian = bytes.join(" ");
+                bytes.reverse();
+                Ok(format!("BE: {}  LE: {}", big_endian, bytes.join(" ")))
+            }
+            ":build" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.build = parse_switch(argument, ":build")?;
+                    self.session.clear_build();
+                }
+                Ok(format!("Build: {}", switch_name(self.build)))
+            }
+            ":build-clear" =>
+            {
+                self.session.clear_build();
+                Ok("Expression cleared".into())
+            }
+            ":stats" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.stats = parse_switch(argument, ":stats")?;
+                }
+                Ok(format!("Stats: {}", switch_name(self.presentation.stats)))
+            }
+            ":trace" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.trace = parse_switch(argument, ":trace")?;
+                }
+                Ok(format!("Trace: {}", switch_name(self.presentation.trace)))
+            }
+            ":prefix" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.format.prefix = parse_switch(argument, ":prefix")?;
+                }
+                Ok(format!("Prefix: {}", switch_name(self.presentation.format.prefix)))
+            }
+            ":base-hint" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.base_hint = parse_switch(argument, ":base-hint")?;
+                }
+                Ok(format!("Base hint: {}", switch_name(self.presentation.base_hint)))
+            }
+            ":pad" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "off" => self.presentation.format.pad_width = None,
+                    _ => self.presentation.format.pad_width = Some(parse_width(argument).ok_or("Usage: :pad 8|16|32|64|off")?),
+                }
+                Ok(self.describe_format())
+            }
+            ":group" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "off" => self.presentation.format.group = None,
+                    _ => self.presentation.format.group = Some(argument.parse().ok().filter(|size| *size > 0).ok_or("Usage: :group N|off")?),
+                }
+                Ok(self.describe_format())
+            }
+            ":group-hex" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "off" => self.presentation.format.hex_group = None,
+                    _ => self.presentation.format.hex_group = Some(argument.parse().ok().filter(|size| *size > 0).ok_or("Usage: :group-hex N|off")?),
+                }
+                Ok(self.describe_format())
+            }
+            ":group-bin" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "off" => self.presentation.format.bin_group = None,
+                    _ => self.presentation.format.bin_group = Some(argument.parse().ok().filter(|size| *size > 0).ok_or("Usage: :group-bin N|off")?),
+                }
+                Ok(self.describe_format())
+            }
+            ":case" =>
+            {
+                match argument
+                {
+                    "" => {}
+                    "upper" => self.presentation.format.uppercase = true,
+                    "lower" => self.presentation.format.uppercase = false,
+                    _ => return Err("Usage: :case upper|lower".into()),
+                }
+                Ok(self.describe_format())
+            }
+            // The layout register maps in datasheets use: `h0000_00FF` at 32 bits
+            ":regfmt" =>
+            {
+                let width = parse_width(argument).ok_or("Usage: :regfmt 8|16|32|64")?;
+                self.presentation.format = FormatOptions { pad_width: Some(width), group: Some(4), hex_group: None, bin_group: None, uppercase: true, prefix: self.presentation.format.prefix, unsigned: false, twos_complement: true };
+                Ok(self.describe_format())
+            }
+            ":codes" =>
+            {
+                if !argument.is_empty()
+                {
+                    self.presentation.codes = parse_switch(argument, ":codes")?;
+                }
+                Ok(format!("Error codes: {}", switch_name(self.presentation.codes)))
+            }
+            _ => Err(format!("Unknown command: {}", name)),
+        }
+    }
+}
+
+fn main() -> rustyline::Result<()> 
+{
+    let options = match parse_args(std::env::args().skip(1))
+    {
+        Ok(options) => options,
+        Err(err) =>
+        {
+            eprintln!("Error: {}", err);
+            std::process::exit(2);
+        }
+    };
+    // Quiet mode is for programs driving the REPL over a pipe: only results and errors are printed
+    let prompt = if options.quiet { "" } else { "Enter an expression (or ':quit' to quit): " };
+
+    if let Some(seed) = options.seed
+    {
+        seed_random(seed);
+    }
+
+    let color = colors_enabled();
+    let config = Config::builder().edit_mode(options.edit_mode.unwrap_or(EditMode::Emacs)).build();
+    let mut rl: Editor<CalcHelper, DefaultHistory> = Editor::with_config(config)?;
+    let mut defaults = Settings { implicit_base: options.radix, timeout: options.timeout, unsigned: options.unsigned, ..Settings::default() };
+    apply_environment(&mut defaults);
+    if let Some(ops) = options.disabled_ops
+    {
+        defaults.disabled_ops = ops;
+    }
+    if let Some(max_input_len) = options.max_input_len
+    {
+        defaults.max_input_len = max_input_len;
+    }
+    rl.set_helper(Some(CalcHelper::new(color, defaults.clone())));
+    let mut session = Session::new();
+    *session.settings_mut() = defaults.clone();
+    let mut presentation = Presentation::default();
+    presentation.format.prefix = !options.no_prefix;
+    let mut repl = Repl
+    {
+        quit: false,
+        session,
+        defaults,
+        presentation_defaults: presentation.clone(),
+        presentation,
+        undo: VecDeque::new(),
+        clipboard: Clipboard::new(),
+        color,
+        quiet: options.quiet,
+        result_prefix: options.result_prefix,
+        result_suffix: options.result_suffix,
+        confirm: false,
+        build: false,
+    };
+
+    // Like `sed -e`: an expression that fails is reported and the rest still run. Any error in the
+    // session, from `-e` or typed in, makes the exit status 1 so scripts feeding the console can tell.
+    let mut failed = false;
+    for expression in &options.expressions
+    {
+        failed |= !repl.run_line(expression);
+        if repl.quit
+        {
+            break;
+        }
+    }
+
+    if let Some(path) = &options.follow
+    {
+        failed |= !follow(&mut repl, path);
+    }
+
+    if let Some(path) = options.lint.as_ref().filter(|_| !repl.quit)
+    {
+        failed |= !lint(&mut repl, path);
+    }
+
+    for expression in options.dump_tokens.iter().filter(|_| !repl.quit)
+    {
+        match repl.session.dump_tokens(expression)
+        {
+            Ok(json) => println!("{}", json),
+            Err(err) =>
+            {
+                repl.report(&err);
+                failed = true;
+            }
+        }
+    }
+
+    if let Some((count, expression)) = options.repeat.as_ref().filter(|_| !repl.quit)
+    {
+        failed |= !repeat(&mut repl, *count, expression);
+    }
+
+    let batch = !options.expressions.is_empty() || options.follow.is_some() || options.lint.is_some() || options.repeat.is_some() || !options.dump_tokens.is_empty();
+    if repl.quit || batch && !options.interactive
+    {
+        std::process::exit(i32::from(failed));
+    }
+    // Piped input is still for a program, but someone typing at the console wants the plain result
+    if io::stdin().is_terminal()
+    {
+        repl.result_prefix.clear();
+        repl.result_suffix.clear();
+        repl.confirm = !options.force;
+    }
+
+    loop 
+    {
+        if let Some(helper) = rl.helper_mut() 
+        {
+            helper.variables = repl.session.variables().iter().map(|(name, _)| name.to_string()).collect();
+            helper.settings = repl.session.settings().clone();
+        }
+
+        match rl.readline(prompt) 
+        {
+            Ok(line) => 
+            {
+                if line.is_empty() 
+                {
+                    continue;
+                }
+                if !options.no_history
+                {
+                    rl.add_history_entry(&line)?;
+                }
+
+                // The old one-letter commands, unless they name a variable: then it is the variable's value that is asked for
+                let alias = match line.trim() 
+                {
+                    "q" => Some(":quit"),
+                    "c" => Some(":clear"),
+                    _ => None,
+                };
+                match alias.filter(|_| repl.session.variable(line.trim()).is_none()) 
+                {
+                    Some(command) => 
+                    {
+                        eprintln!("Note: `{}` is deprecated, use {}", line.trim(), command);
+                        failed |= !repl.run_line(command);
+                    }
+                    None => failed |= !repl.run_line(&line),
+                }
+                if repl.quit 
+                {
+                    break;
+                }
+            }
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(err) => 
+            {
+                eprintln!("Error: {}", err);
+                failed = true;
+                break;
+            }
+        }
+    }
+
+    if failed
+    {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // A console as `main` sets it up with no options, not at a terminal
+    fn repl() -> Repl
+    {
+        Repl
+        {
+            quit: false,
+            session: Session::new(),
+            defaults: Settings::default(),
+            presentation_defaults: Presentation::default(),
+            presentation: Presentation::default(),
+            undo: VecDeque::new(),
+            clipboard: Clipboard::new(),
+            color: false,
+            quiet: false,
+            result_prefix: String::new(),
+            result_suffix: String::new(),
+            confirm: false,
+            build: false,
+        }
+    }
+
+    #[test]
+    fn comments_go_before_segments_and_assignments()
+    {
+        let line = "mask = hFF ; shifted = mask << d4 h # both ; still the comment";
+        let segments: Vec<&str> = split_segments(line).collect();
+        assert_eq!(segments, ["mask = hFF", "shifted = mask << d4 h"]);
+
+        let mut repl = repl();
+        let outputs: Vec<String> = segments.iter().map(|segment| repl.eval_segment(segment).unwrap()).collect();
+        assert_eq!(outputs, ["mask = hFF", "shifted = hFF0"]);
+        assert_eq!(repl.session.variable("shifted").map(|num| num.value), Some(0xFF0));
+    }
+
+    #[test]
+    fn an_oversized_line_is_rejected_before_any_segment_runs()
+    {
+        let mut repl = repl();
+        repl.session.settings_mut().max_input_len = 16;
+        repl.presentation.trace = true;
+        // Every segment is short enough on its own; the line as a whole is not
+        assert!(!repl.run_line("x = d1 ; y = (d2)"));
+        assert_eq!(repl.session.variable("x"), None);
+        assert_eq!(repl.session.last_result(), None);
+        assert!(repl.run_line("x = (d1)"));
+        assert_eq!(repl.session.variable("x").map(|num| num.value), Some(1));
+    }
+
+    // `:bytes` after evaluating `value` at `width`
+    fn bytes_of(value: &str, width: u32) -> Result<String, String>
+    {
+        let mut repl = repl();
+        repl.session.settings_mut().width = width;
+        repl.eval_segment(value).map_err(|err| err.to_string())?;
+        repl.run_command(":bytes")
+    }
+
+    #[test]
+    fn bytes_in_both_orders_at_each_width()
+    {
+        assert_eq!(bytes_of("hBEEF", 16), Ok("BE: BE EF  LE: EF BE".into()));
+        assert_eq!(bytes_of("hDEADBEEF", 32), Ok("BE: DE AD BE EF  LE: EF BE AD DE".into()));
+        assert_eq!(bytes_of("h0123456789ABCDEF", 64), Ok("BE: 01 23 45 67 89 AB CD EF  LE: EF CD AB 89 67 45 23 01".into()));
+        assert_eq!(bytes_of("d-2", 16), Ok("BE: FF FE  LE: FE FF".into()));
+        assert_eq!(bytes_of("h1_0000", 16), Err("h10000 doesn't fit in 16 bits".into()));
+    }
+
+    #[test]
+    fn regfmt_shows_negative_values_as_register_bits()
+    {
+        let mut repl = repl();
+        repl.run_command(":regfmt 32").unwrap();
+        let format = |repl: &Repl, value: i64, base: Base| repl.format(&Number { value, base });
+        assert_eq!(format(&repl, 0xFF, Base::Hexadecimal), "h0000_00FF");
+        assert_eq!(format(&repl, -1, Base::Hexadecimal), "hFFFF_FFFF");
+        assert_eq!(format(&repl, -2, Base::Binary), "b1111_1111_1111_1111_1111_1111_1111_1110");
+        // Too wide for the register, so it keeps its sign
+        assert_eq!(format(&repl, -(1 << 40), Base::Hexadecimal), "h-100_0000_0000");
+        repl.run_command(":regfmt 8").unwrap();
+        assert_eq!(format(&repl, -128, Base::Hexadecimal), "h80");
+    }
+
+    #[test]
+    fn crlf_lines_run_like_lf_lines()
+    {
+        let mut repl = repl();
+        assert!(repl.run_line("x = d5 ; y = x * d2 h\r"));
+        assert!(repl.run_line("z = y + d1 # note ; not a segment\r"));
+        assert!(repl.run_line(":width 16\r"));
+        assert_eq!(repl.session.variable("y").map(|num| (num.value, num.base)), Some((10, Base::Hexadecimal)));
+        assert_eq!(repl.session.variable("z").map(|num| num.value), Some(11));
+        assert_eq!(repl.session.settings().width, 16);
+    }
+
+    #[test]
+    fn emit_writes_literals_for_each_language()
+    {
+        let format = FormatOptions::default();
+        let emit = |value, base, language| emit_literal(&Number { value, base }, language, &format);
+        assert_eq!(emit(255, Base::Hexadecimal, Emit::C), "0xFF");
+        assert_eq!(emit(255, Base::Binary, Emit::C), "0b11111111");
+        assert_eq!(emit(8, Base::Octal, Emit::C), "010");
+        assert_eq!(emit(1_000_000, Base::Decimal, Emit::C), "1000000");
+        assert_eq!(emit(-255, Base::Hexadecimal, Emit::C), "-0xFF");
+
+        assert_eq!(emit(0xDEADBEEF, Base::Hexadecimal, Emit::Rust), "0xDEAD_BEEF");
+        assert_eq!(emit(255, Base::Binary, Emit::Rust), "0b1111_1111");
+        assert_eq!(emit(8, Base::Octal, Emit::Rust), "0o10");
+        assert_eq!(emit(1_000_000, Base::Decimal, Emit::Rust), "1_000_000");
+
+        assert_eq!(emit(0xDEADBEEF, Base::Hexadecimal, Emit::Python), "0xDEADBEEF");
+        assert_eq!(emit(8, Base::Octal, Emit::Python), "0o10");
+        assert_eq!(emit(1_000_000, Base::Decimal, Emit::Python), "1000000");
+
+        let mut repl = repl();
+        repl.run_command(":emit rust").unwrap();
+        assert_eq!(repl.eval_segment("hFFFF + d1"), Ok("0x1_0000".into()));
+    }
+
+    #[test]
+    fn copy_takes_the_result_as_printed()
+    {
+        let mut unsigned = repl();
+        unsigned.session.settings_mut().unsigned = true;
+        unsigned.eval_segment("hFFFFFFFFFFFFFFFF d").unwrap();
+        let last = unsigned.session.last_result().unwrap().clone();
+        assert_eq!(unsigned.shown(&last), "d18446744073709551615");
+
+        let mut repl = repl();
+        let printed = repl.eval_segment("hDEADBEEF").unwrap();
+        let last = repl.session.last_result().unwrap().clone();
+        assert_eq!(repl.shown(&last), printed);
+        repl.run_command(":regfmt 32").unwrap();
+        repl.run_command(":case lower").unwrap();
+        assert_eq!(repl.shown(&last), "hdead_beef");
+        repl.run_command(":prefix off").unwrap();
+        assert_eq!(repl.shown(&last), "dead_beef");
+        repl.run_command(":emit c").unwrap();
+        assert_eq!(repl.shown(&last), "0xdeadbeef");
+    }
+
+    #[test]
+    fn bytes_checks_the_unsigned_range_in_unsigned_mode()
+    {
+        let mut repl = repl();
+        repl.session.settings_mut().unsigned = true;
+        repl.session.settings_mut().width = 16;
+        repl.eval_segment("hFFFF").unwrap();
+        assert_eq!(repl.run_command(":bytes"), Ok("BE: FF FF  LE: FF FF".into()));
+        // All ones is u64::MAX here, not -1
+        repl.eval_segment("hFFFFFFFFFFFFFFFF d").unwrap();
+        assert_eq!(repl.run_command(":bytes"), Err("d18446744073709551615 doesn't fit in 16 bits".into()));
+        repl.run_command(":group 3").unwrap();
+        assert_eq!(repl.run_command(":bytes"), Err("d18_446_744_073_709_551_615 doesn't fit in 16 bits".into()));
+    }
+
+    #[test]
+    fn human_size_switches_units_at_the_thresholds()
+    {
+        let binary = |bytes| human_size(bytes, ByteUnits::Binary);
+        assert_eq!(binary(1023), None);
+        assert_eq!(binary(1024), Some("1.0 KiB".into()));
+        assert_eq!(binary(1536), Some("1.5 KiB".into()));
+        assert_eq!(binary(1_048_576), Some("1.0 MiB".into()));
+        // Just below a MiB rounds up to it rather than showing 1024.0 KiB
+        assert_eq!(binary(1_048_575), Some("1.0 MiB".into()));
+        assert_eq!(binary(1_048_576 - 52 * 1024), Some("972.0 KiB".into()));
+        assert_eq!(binary(1 << 30), Some("1.0 GiB".into()));
+        assert_eq!(binary(i64::MAX), Some("8.0 EiB".into()));
+        assert_eq!(binary(-2048), None);
+
+        let si = |bytes| human_size(bytes, ByteUnits::Si);
+        assert_eq!(si(999), None);
+        assert_eq!(si(1000), Some("1.0 kB".into()));
+        assert_eq!(si(1024), Some("1.0 kB".into()));
+        assert_eq!(si(999_949), Some("999.9 kB".into()));
+        assert_eq!(si(999_950), Some("1.0 MB".into()));
+        assert_eq!(si(1_000_000), Some("1.0 MB".into()));
+        assert_eq!(si(1_048_576), Some("1.0 MB".into()));
+    }
+
+    #[test]
+    fn base_reads_unprefixed_numbers_and_leaves_prefixed_ones_alone()
+    {
+        let mut repl = repl();
+        assert_eq!(repl.run_command(":base hex"), Ok("Input base: hexadecimal, output base: hexadecimal".into()));
+        assert_eq!(repl.eval_segment("FF + 1"), Ok("h100".into()));
+        assert_eq!(repl.eval_segment("d10"), Ok("hA".into()));
+        assert_eq!(repl.eval_segment("d10 + d5"), Ok("hF".into()));
+        assert_eq!(repl.eval_segment("FF + d10 d"), Ok("d265".into()));
+        assert_eq!(repl.run_command(":base off"), Ok("Input base: prefix required, output base: inferred".into()));
+        assert!(repl.eval_segment("FF + 1").is_err());
+        assert_eq!(repl.eval_segment("d10 + d5"), Ok("d15".into()));
     }
-    Ok(())
 }
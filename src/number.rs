@@ -0,0 +1,363 @@
+use std::fmt;
+use std::num::IntErrorKind;
+
+use crate::error::CalcError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Base
+{
+    Decimal,
+    Hexadecimal,
+    Binary,
+    Octal,
+}
+
+impl Base
+{
+    pub const ALL: [Base; 4] = [Base::Decimal, Base::Hexadecimal, Base::Binary, Base::Octal];
+
+    pub fn from_char(c: char) -> Result<Self, CalcError>
+    {
+        match c
+        {
+            'd' => Ok(Base::Decimal),
+            'h' => Ok(Base::Hexadecimal),
+            'b' => Ok(Base::Binary),
+            'o' => Ok(Base::Octal),
+            _ => Err(CalcError::InvalidBase(c)),
+        }
+    }
+
+    // A base spelled out for a command: its prefix letter, a short name or its full name (`h`, `hex`, `hexadecimal`).
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "d" | "dec" | "decimal" => Some(Base::Decimal),
+            "h" | "hex" | "hexadecimal" => Some(Base::Hexadecimal),
+            "b" | "bin" | "binary" => Some(Base::Binary),
+            "o" | "oct" | "octal" => Some(Base::Octal),
+            _ => None,
+        }
+    }
+
+    pub fn from_radix(radix: u32) -> Option<Self>
+    {
+        Base::ALL.into_iter().find(|base| base.radix() == radix)
+    }
+
+    pub fn prefix(&self) -> char
+    {
+        match self
+        {
+            Base::Decimal => 'd',
+            Base::Hexadecimal => 'h',
+            Base::Binary => 'b',
+            Base::Octal => 'o',
+        }
+    }
+
+    pub fn radix(&self) -> u32
+    {
+        match self
+        {
+            Base::Decimal => 10,
+            Base::Hexadecimal => 16,
+            Base::Binary => 2,
+            Base::Octal => 8,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Base::Decimal => "decimal",
+            Base::Hexadecimal => "hexadecimal",
+            Base::Binary => "binary",
+            Base::Octal => "octal",
+        }
+    }
+}
+
+// How a value is written out. `Default` is the plain form, as in `hFF`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FormatOptions
+{
+    // Zero-pad to as many digits as the largest unsigned value of this many bits takes
+    pub pad_width: Option<u32>,
+    // Separate the digits with `_` into groups of this many, counted from the right
+    pub group: Option<usize>,
+    // Replaces `group` for hex, where 2 digits make a byte
+    pub hex_group: Option<usize>,
+    // Replaces `group` for binary, where 4 digits make a nibble
+    pub bin_group: Option<usize>,
+    // Upper- or lowercase hex letters
+    pub uppercase: bool,
+    // Off: just the sign and digits (`FF`), for tools that already know the base. Such output can't be parsed back.
+    pub prefix: bool,
+    // Read the 64 bits as unsigned, so `d-1` is written `d18446744073709551615`
+    pub unsigned: bool,
+    // Write a negative value that fits in `pad_width` bits as those bits in two's complement, the way a register
+    // holds it: `d-1` at 32 bits is `hFFFFFFFF`. Such output reads back as the unsigned value.
+    pub twos_complement: bool,
+}
+
+impl Default for FormatOptions
+{
+    fn default() -> Self
+    {
+        FormatOptions { pad_width: None, group: None, hex_group: None, bin_group: None, uppercase: true, prefix: true, unsigned: false, twos_complement: false }
+    }
+}
+
+fn digits_of(magnitude: u64, base: Base, uppercase: bool) -> String
+{
+    match base
+    {
+        Base::Decimal => magnitude.to_string(),
+        Base::Hexadecimal if uppercase => format!("{:X}", magnitude),
+        Base::Hexadecimal => format!("{:x}", magnitude),
+        Base::Binary => format!("{:b}", magnitude),
+        Base::Octal => format!("{:o}", magnitude),
+    }
+}
+
+// Renders a value with its base prefix. Negative values are written sign-magnitude (`h-A`)
+// rather than as two's complement, so that anything printed can be parsed back.
+pub fn format_value(value: i64, base: Base) -> String
+{
+    format_with(value, base, &FormatOptions::default())
+}
+
+// Like `format_value`, laid out as `options` says. Padding and `_` groups still parse back.
+pub fn format_with(value: i64, base: Base, options: &FormatOptions) -> String
+{
+    let register = options.pad_width.filter(|width| options.twos_complement && value < 0 && fits_width(value, *width));
+    let sign = if value < 0 && !options.unsigned && register.is_none() { "-" } else { "" };
+    let magnitude = match register
+    {
+        Some(width) => value as u64 & (u64::MAX >> (64 - width.clamp(1, 64))),
+        None if options.unsigned => value as u64,
+        None => value.unsigned_abs(),
+    };
+    let mut digits = digits_of(magnitude, base, options.uppercase);
+
+    if let Some(width) = options.pad_width
+    {
+        let len = digits_of(u64::MAX >> (64 - width.clamp(1, 64)), base, true).len();
+        if digits.len() < len
+        {
+            digits.insert_str(0, &"0".repeat(len - digits.len()));
+        }
+    }
+
+    let group = match base
+    {
+        Base::Hexadecimal => options.hex_group.or(options.group),
+        Base::Binary => options.bin_group.or(options.group),
+        _ => options.group,
+    };
+    // Only the digits of the magnitude are grouped and the sign goes in front afterwards, so `d-1000` is
+    // `d-1_000`, and a value with no more digits than a group (`d0`, `d100`) gets no separator at all
+    if let Some(size) = group.filter(|size| *size > 0)
+    {
+        let mut grouped = String::with_capacity(digits.len() * 2);
+        for (i, c) in digits.chars().enumerate()
+        {
+            if i > 0 && (digits.len() - i).is_multiple_of(size)
+            {
+                grouped.push('_');
+            }
+            grouped.push(c);
+        }
+        digits = grouped;
+    }
+
+    if options.prefix
+    {
+        format!("{}{}{}", base.prefix(), sign, digits)
+    }
+    else
+    {
+        format!("{}{}", sign, digits)
+    }
+}
+
+// Whether a value can be stored in `width` bits, read as either a signed or an unsigned integer.
+pub fn fits_width(value: i64, width: u32) -> bool
+{
+    width >= 64 || (-(1i64 << (width - 1))..1i64 << width).contains(&value)
+}
+
+// Whether a value can be stored in `width` bits when its 64 bits are read as unsigned, as in `:unsigned on`.
+pub fn fits_width_unsigned(value: i64, width: u32) -> bool
+{
+    width >= 64 || (value as u64) >> width == 0
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Number
+{
+    pub value: i64,
+    pub base: Base,
+}
+
+impl Number
+{
+    pub fn parse(input: &str) -> Result<Self, CalcError>
+    {
+        Number::parse_as(input, false)
+    }
+
+    // Like `parse`, but `unsigned` takes anything up to u64::MAX, stored as its bit pattern, and no sign.
+    pub fn parse_as(input: &str, unsigned: bool) -> Result<Self, CalcError>
+    {
+        if input.is_empty()
+        {
+            return Err(CalcError::EmptyNumber);
+        }
+
+        let base = Base::from_char(input.chars().next().unwrap())?; // The first character is the base
+        let value_str = &input[1..]; // The rest is the value
+
+        // A minus right after the prefix makes the literal negative (`h-FF`), in every base. `from_str_radix`
+        // would also take a `+`, or a sign after `_`, so the sign is handled here and the digits parsed unsigned.
+        let (negative, digits) = match value_str.strip_prefix('-')
+        {
+            Some(digits) => (true, digits.replace('_', "")),
+            None => (false, value_str.replace('_', "")),
+        };
+        // `_` only goes between two digits: not first (`d_5`), last (`d5_`) or twice in a row (`d5__0`)
+        let written = value_str.strip_prefix('-').unwrap_or(value_str);
+        if written.is_empty()
+        {
+            return Err(CalcError::MissingDigits(base));
+        }
+        let misplaced = written.starts_with('_') || written.ends_with('_') || written.contains("__");
+        if digits.starts_with(['+', '-']) || (negative && unsigned) || misplaced
+        {
+            return Err(CalcError::InvalidNumber(base, value_str.to_string()));
+        }
+
+        let too_large = || CalcError::NumberTooLarge(value_str.to_string());
+        let magnitude = u64::from_str_radix(&digits, base.radix()).map_err(|err| match err.kind()
+        {
+            IntErrorKind::PosOverflow if unsigned => CalcError::UnsignedTooLarge(value_str.to_string()),
+            IntErrorKind::PosOverflow => too_large(),
+            _ => match digits.chars().find(|c| !c.is_digit(base.radix()))
+            {
+                Some(digit) => CalcError::InvalidDigit(base, digit, value_str.to_string()),
+                None => CalcError::InvalidNumber(base, value_str.to_string()),
+            },
+        })?;
+        // The magnitude of i64::MIN is one more than i64::MAX, so only a negative literal can reach it
+        let value = if unsigned
+        {
+            magnitude as i64
+        }
+        else if negative && magnitude == i64::MIN.unsigned_abs()
+        {
+            i64::MIN
+        }
+        else
+        {
+            let value = i64::try_from(magnitude).map_err(|_| too_large())?;
+            if negative { -value } else { value }
+        };
+
+        Ok(Number { value, base })
+    }
+
+    pub fn format(&self) -> String
+    {
+        format_value(self.value, self.base)
+    }
+}
+
+impl fmt::Display for Number
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}", self.format())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::token::{tokenize, Token};
+
+    #[test]
+    fn format_parses_back_in_every_base()
+    {
+        let mut values = vec![i64::MIN, i64::MIN + 1, -256, -255, -1, 0, 1, 255, 256, i64::MAX - 1, i64::MAX];
+        values.extend((-1000..=1000).step_by(7));
+        values.extend((0..64).map(|shift| 1i64 << shift));
+        values.extend((0..64).map(|shift| (1i64 << shift).wrapping_neg()));
+        for value in values
+        {
+            for base in Base::ALL
+            {
+                let text = format_value(value, base);
+                assert_eq!(Number::parse(&text), Ok(Number { value, base }), "{}", text);
+                assert_eq!(tokenize(&text), Ok(vec![Token::Number(Number { value, base })]), "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn hex_groups_of_two_and_four()
+    {
+        let value = 0xDEAD_BEEF;
+        let bytes = FormatOptions { hex_group: Some(2), group: Some(3), ..FormatOptions::default() };
+        assert_eq!(format_with(value, Base::Hexadecimal, &bytes), "hDE_AD_BE_EF");
+        let words = FormatOptions { hex_group: Some(4), ..bytes };
+        assert_eq!(format_with(value, Base::Hexadecimal, &words), "hDEAD_BEEF");
+        // The other bases keep the `:group` size
+        assert_eq!(format_with(value, Base::Decimal, &words), "d3_735_928_559");
+    }
+
+    #[test]
+    fn grouping_leaves_the_sign_and_short_values_alone()
+    {
+        let options = FormatOptions { group: Some(3), ..FormatOptions::default() };
+        let group = |value| format_with(value, Base::Decimal, &options);
+        assert_eq!(group(-1000), "d-1_000");
+        assert_eq!(group(0), "d0");
+        assert_eq!(group(7), "d7");
+        assert_eq!(group(42), "d42");
+        assert_eq!(group(100), "d100");
+        assert_eq!(group(-100), "d-100");
+        assert_eq!(group(1000), "d1_000");
+        assert_eq!(group(1_000_000), "d1_000_000");
+    }
+
+    #[test]
+    fn binary_in_nibbles_at_16_bits()
+    {
+        let nibbles = FormatOptions { pad_width: Some(16), bin_group: Some(4), ..FormatOptions::default() };
+        assert_eq!(format_with(0xFF, Base::Binary, &nibbles), "b0000_0000_1111_1111");
+        assert_eq!(format_with(0xA5C3, Base::Binary, &nibbles), "b1010_0101_1100_0011");
+        assert_eq!(format_with(0, Base::Binary, &nibbles), "b0000_0000_0000_0000");
+        // Only binary takes the nibble size
+        assert_eq!(format_with(0xFF, Base::Hexadecimal, &nibbles), "h00FF");
+    }
+
+    #[test]
+    fn parse_errors_say_what_is_wrong()
+    {
+        assert_eq!(Number::parse("d"), Err(CalcError::MissingDigits(Base::Decimal)));
+        assert_eq!(Number::parse("h-"), Err(CalcError::MissingDigits(Base::Hexadecimal)));
+        assert_eq!(Number::parse("d5A"), Err(CalcError::InvalidDigit(Base::Decimal, 'A', "5A".into())));
+        assert_eq!(Number::parse("b102"), Err(CalcError::InvalidDigit(Base::Binary, '2', "102".into())));
+        assert_eq!(Number::parse("d9223372036854775808"), Err(CalcError::NumberTooLarge("9223372036854775808".into())));
+        assert_eq!(Number::parse("d-9223372036854775808"), Ok(Number { value: i64::MIN, base: Base::Decimal }));
+        assert_eq!(Number::parse_as("h10000000000000000", true), Err(CalcError::UnsignedTooLarge("10000000000000000".into())));
+
+        assert_eq!(CalcError::MissingDigits(Base::Decimal).to_string(), "No digits after the decimal prefix 'd'");
+        assert_eq!(CalcError::InvalidDigit(Base::Decimal, 'A', "5A".into()).to_string(), "'A' is not a decimal digit: 5A");
+        assert_eq!(CalcError::NumberTooLarge("9223372036854775808".into()).to_string(), "Number too large for i64: 9223372036854775808");
+    }
+}
@@ -0,0 +1,998 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::CalcError;
+use crate::eval::shunting_yard;
+use crate::expr::{eval_ast, Expr};
+use crate::function::Function;
+use crate::number::{format_with, Base, FormatOptions, Number};
+use crate::settings::Settings;
+use crate::token::{is_identifier, is_implicit_number, is_number_literal, tokenize_lenient_with, tokenize_spans, tokenize_with, Operation, Token};
+
+// What a successful evaluation produced; `variable` is set when it was an assignment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalResult
+{
+    pub variable: Option<String>,
+    pub number: Number,
+    // Every value with its label when the expression gave several, like `divmod`. `number` is then the first.
+    pub parts: Vec<(&'static str, Number)>,
+    // Each number literal as the tokenizer read it, in input order, for checking how typed text was understood
+    pub literals: Vec<Number>,
+    pub stats: EvalStats,
+}
+
+// How much work an evaluation took.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EvalStats
+{
+    // Tokens the input was split into, parentheses included
+    pub tokens: usize,
+    // Operators and functions applied
+    pub operations: usize,
+}
+
+impl EvalResult
+{
+    // The result as `Display` writes it, with the numbers laid out as `options` says.
+    pub fn display_with(&self, options: &FormatOptions) -> String
+    {
+        self.display_using(|num| format_with(num.value, num.base, options))
+    }
+
+    // The result as `Display` writes it, with each number written by `format`.
+    pub fn display_using(&self, format: impl Fn(&Number) -> String) -> String
+    {
+        if !self.parts.is_empty()
+        {
+            let parts: Vec<String> = self.parts.iter().map(|(label, num)| format!("{}={}", label, format(num))).collect();
+            return parts.join(" ");
+        }
+
+        match &self.variable
+        {
+            Some(name) => format!("{} = {}", name, format(&self.number)),
+            None => format(&self.number),
+        }
+    }
+}
+
+impl fmt::Display for EvalResult
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}", self.display_with(&FormatOptions::default()))
+    }
+}
+
+// Memory-size magic numbers every session starts with. They are ordinary variables, so they can be redefined.
+pub const CONSTANTS: [(&str, i64); 4] = [("KB", 1 << 10), ("MB", 1 << 20), ("GB", 1 << 30), ("PAGE", 4096)];
+
+// The name that stands for the last result in an expression, like in Python's console: `d5 d`, then `_ + d1 d`.
+// It isn't a variable and can't be assigned to.
+pub const LAST_RESULT: &str = "_";
+
+// What a host program keeps between calls to `eval_with`: variables, functions, the last result and the
+// settings (bit width, input and output bases, ...). It is the same state the console works on.
+pub type EvalContext = Session;
+
+// Evaluates `input` against `ctx`, for embedders that hold the state themselves. Same as `ctx.eval(input)`.
+pub fn eval_with(input: &str, ctx: &mut EvalContext) -> Result<EvalResult, CalcError>
+{
+    ctx.eval(input)
+}
+
+// How deep user functions may call each other. Recursion is fine as long as a conditional ends it; this
+// stops one that never does, like `def f(n) = f(n + d1)`, before it exhausts the stack.
+const MAX_CALL_DEPTH: usize = 64;
+
+// The most rows `table` writes out, so that a mistyped bound can't flood the console.
+const MAX_TABLE_ROWS: i64 = 1024;
+
+// A function defined with `def name(a, b) = expression`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserFunction
+{
+    pub params: Vec<String>,
+    // Kept as typed, and evaluated afresh with the arguments bound to `params` on every call
+    pub body: String,
+}
+
+// Everything a calculator session accumulates. `Default` is the state a new session starts in,
+// and `reset` puts everything back to it.
+#[derive(Debug, Clone)]
+pub struct Session
+{
+    // Only the built-in constants are defined
+    variables: HashMap<String, Number>,
+    // No functions are defined with `def`
+    functions: HashMap<String, UserFunction>,
+    // There is no previous result
+    last_result: Option<Number>,
+    // Every mode starts out matching Rust's integer semantics
+    settings: Settings,
+    // Nothing has been entered with `build`
+    building: String,
+}
+
+impl Default for Session
+{
+    fn default() -> Self
+    {
+        let variables = CONSTANTS.iter().map(|(name, value)| (name.to_string(), Number { value: *value, base: Base::Decimal })).collect();
+        Session { variables, functions: HashMap::new(), last_result: None, settings: Settings::default(), building: String::new() }
+    }
+}
+
+impl Session
+{
+    pub fn new() -> Self
+    {
+        Session::default()
+    }
+
+    pub fn reset(&mut self)
+    {
+        *self = Session::default();
+    }
+
+    pub fn last_result(&self) -> Option<&Number>
+    {
+        self.last_result.as_ref()
+    }
+
+    pub fn settings(&self) -> &Settings
+    {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut Settings
+    {
+        &mut self.settings
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Number>
+    {
+        self.variables.get(name)
+    }
+
+    // Every defined variable, sorted by name.
+    pub fn variables(&self) -> Vec<(&str, &Number)>
+    {
+        let mut variables: Vec<_> = self.variables.iter().map(|(name, num)| (name.as_str(), num)).collect();
+        variables.sort_by_key(|(name, _)| *name);
+        variables
+    }
+
+    // Every function defined with `def`, sorted by name.
+    pub fn functions(&self) -> Vec<(&str, &UserFunction)>
+    {
+        let mut functions: Vec<_> = self.functions.iter().map(|(name, function)| (name.as_str(), function)).collect();
+        functions.sort_by_key(|(name, _)| *name);
+        functions
+    }
+
+    // Handles `def name(a, b) = expression`, returning the new function's signature. The body may use its
+    // parameters, variables that are already defined, and any function.
+    pub fn define_function(&mut self, input: &str) -> Result<String, CalcError>
+    {
+        let definition = input.trim().strip_prefix("def ").ok_or(CalcError::InvalidDefinition)?;
+        let (signature, body) = definition.split_once('=').ok_or(CalcError::InvalidDefinition)?;
+        let (name, params) = signature.trim().strip_suffix(')').and_then(|signature| signature.split_once('(')).ok_or(CalcError::InvalidDefinition)?;
+
+        let name = name.trim();
+        if Function::from_name(name).is_some() || name == "table"
+        {
+            return Err(CalcError::BuiltinFunction(name.to_string()));
+        }
+        if !is_identifier(name)
+        {
+            return Err(CalcError::InvalidDefinition);
+        }
+
+        // A call needs at least one argument, since `name()` is empty parentheses
+        let params: Vec<String> = params.split(',').map(|param| param.trim().to_string()).collect();
+        if let Some(param) = params.iter().find(|param| !is_identifier(param))
+        {
+            return Err(if param.is_empty() { CalcError::InvalidDefinition } else { invalid_name(param) });
+        }
+        if params.iter().enumerate().any(|(i, param)| params[..i].contains(param))
+        {
+            return Err(CalcError::InvalidDefinition);
+        }
+
+        let body = body.trim();
+        if body.is_empty()
+        {
+            return Err(CalcError::EmptyExpression);
+        }
+        let tokens = tokenize_with(body, &self.settings)?;
+        // `_` is read when the function is called, so it needn't have a value yet
+        let undefined = tokens.iter().find_map(|token| match token
+        {
+            Token::Variable(var) if !params.contains(var) && !self.variables.contains_key(var) && var != LAST_RESULT => Some(var.clone()),
+            _ => None,
+        });
+        if let Some(var) = undefined
+        {
+            return Err(CalcError::UndefinedVariable(var));
+        }
+        match Expr::from_rpn(shunting_yard(tokens)?)?
+        {
+            // A call gives a single value
+            Expr::Call(function, _) if function.labels().len() > 1 => return Err(CalcError::MultipleValues(function.name().to_string())),
+            _ => {}
+        }
+
+        let signature = format!("{}({})", name, params.join(", "));
+        self.functions.insert(name.to_string(), UserFunction { params, body: body.to_string() });
+        Ok(signature)
+    }
+
+    pub fn define_var(&mut self, name: &str, number: Number) -> Result<(), CalcError>
+    {
+        if !is_identifier(name) || name == LAST_RESULT
+        {
+            return Err(invalid_name(name));
+        }
+
+        self.variables.insert(name.to_string(), number);
+        Ok(())
+    }
+
+    // Evaluates a single expression or assignment, remembering its value as the last result.
+    pub fn eval(&mut self, input: &str) -> Result<EvalResult, CalcError>
+    {
+        if input.len() > self.settings.max_input_len
+        {
+            return Err(CalcError::InputTooLong(self.settings.max_input_len));
+        }
+
+        let input = input.trim();
+        if input.is_empty()
+        {
+            return Err(CalcError::EmptyExpression);
+        }
+
+        let result = match input.split_once('=')
+        {
+            Some((name, definition)) => self.assign(name.trim(), definition.trim())?,
+            None => self.evaluate(input)?,
+        };
+
+        self.last_result = Some(result.number.clone());
+        Ok(result)
+    }
+
+    // Adds `line` to the end of the expression being built, after a space, and evaluates the whole of it with the
+    // usual precedence, remembering its value as the last result. While the expression is only unfinished, like
+    // `d5 +` or `(d5`, there is no value yet and `None` comes back. A line that makes it wrong in any other way is
+    // an error and is left out.
+    pub fn build(&mut self, line: &str) -> Result<Option<EvalResult>, CalcError>
+    {
+        let line = line.trim();
+        let expression = if self.building.is_empty() { line.to_string() } else { format!("{} {}", self.building, line) };
+        if expression.len() > self.settings.max_input_len
+        {
+            return Err(CalcError::InputTooLong(self.settings.max_input_len));
+        }
+
+        match self.evaluate(&expression)
+        {
+            Ok(result) =>
+            {
+                self.building = expression;
+                self.last_result = Some(result.number.clone());
+                Ok(Some(result))
+            }
+            Err(err) if self.unfinished(&expression, &err) =>
+            {
+                self.building = expression;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // The expression `build` has put together so far.
+    pub fn built(&self) -> &str
+    {
+        &self.building
+    }
+
+    pub fn clear_build(&mut self)
+    {
+        self.building.clear();
+    }
+
+    // Whether `err` only means more is to come: a bracket or `?` still open, or an operator at the very end.
+    fn unfinished(&self, expression: &str, err: &CalcError) -> bool
+    {
+        match err
+        {
+            CalcError::UnmatchedOpenParen | CalcError::UnmatchedConditional => true,
+            CalcError::MissingOperand(_) => match tokenize_with(expression, &self.settings).ok().and_then(|tokens| tokens.last().cloned())
+            {
+                Some(Token::Operation(op)) => op != Operation::CloseParen,
+                Some(Token::Colon | Token::Comma) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // How an expression is grouped, as an S-expression, without evaluating it. Any output base is left out.
+    pub fn sexpr(&self, input: &str) -> Result<String, CalcError>
+    {
+        let (_, expr) = split_output_base(input.trim(), self.settings.implicit_base)?;
+        let rpn_tokens = shunting_yard(tokenize_with(expr, &self.settings)?)?;
+        Ok(Expr::from_rpn(rpn_tokens)?.to_sexpr())
+    }
+
+    // The tokens of an expression as a JSON array, for editors and debuggers that want the parse without an
+    // evaluation. Each token has its `kind`, the `text` it was read from and the byte range `start`..`end` of
+    // that text in `input`, plus what the kind needs: a number's `value` and `base`, a name, or an operator's
+    // symbol. An output base at the end isn't a token and is left out.
+    pub fn dump_tokens(&self, input: &str) -> Result<String, CalcError>
+    {
+        let (_, expr) = split_output_base(input.trim(), self.settings.implicit_base)?;
+        // `expr` is a slice of `input`, so the distance between their starts is where it begins
+        let start = expr.as_ptr() as usize - input.as_ptr() as usize;
+
+        let tokens: Vec<String> = tokenize_spans(expr, &self.settings)?
+            .into_iter()
+            .map(|(token, span)|
+            {
+                let fields = match token
+                {
+                    Token::Number(num) =>
+                    {
+                        let value = if self.settings.unsigned { (num.value as u64).to_string() } else { num.value.to_string() };
+                        format!(r#""kind":"number","value":{},"base":"{}""#, value, num.base.name())
+                    }
+                    Token::Variable(name) => format!(r#""kind":"variable","name":"{}""#, name),
+                    Token::Operation(Operation::OpenParen) => r#""kind":"open_paren""#.to_string(),
+                    Token::Operation(Operation::CloseParen) => r#""kind":"close_paren""#.to_string(),
+                    Token::Operation(op) => format!(r#""kind":"operator","symbol":"{}","unary":{}"#, op.symbol(), op == Operation::Negate),
+                    Token::Function(function) => format!(r#""kind":"function","name":"{}""#, function.name()),
+                    Token::UserFunction(name) => format!(r#""kind":"user_function","name":"{}""#, name),
+                    Token::Comma => r#""kind":"comma""#.to_string(),
+                    Token::Colon => r#""kind":"colon""#.to_string(),
+                    // Only `shunting_yard` writes calls out
+                    Token::Call(..) | Token::UserCall(..) => unreachable!(),
+                };
+                format!(r#"{{{},"text":{},"start":{},"end":{}}}"#, fields, json_string(&expr[span.clone()]), start + span.start, start + span.end)
+            })
+            .collect();
+        Ok(format!("[{}]", tokens.join(",")))
+    }
+
+    // The expression with everything that doesn't depend on a variable or user function worked out.
+    pub fn simplify(&self, input: &str) -> Result<Expr, CalcError>
+    {
+        let (_, expr) = split_output_base(input.trim(), self.settings.implicit_base)?;
+        let rpn_tokens = shunting_yard(tokenize_with(expr, &self.settings)?)?;
+        Expr::from_rpn(rpn_tokens)?.simplify(&self.settings)
+    }
+
+    // The steps evaluating an expression takes, in words. See `Expr::explain`.
+    pub fn explain(&self, input: &str) -> Result<String, CalcError>
+    {
+        let (output_base, expr) = split_output_base(input.trim(), self.settings.implicit_base)?;
+        let tokens = tokenize_with(expr, &self.settings)?;
+        let base = output_base.or(self.settings.output_base).unwrap_or_else(|| self.inferred_base(&tokens));
+        let variables = self.scope(&tokens)?;
+        Expr::from_rpn(shunting_yard(tokens)?)?.explain(&variables, &self.settings, &|name, args| self.call(name, args, 0), base)
+    }
+
+    // The text and value of each parenthesized group at the top level of an expression or assignment, like
+    // `(d2 + d3)` and 5 in `(d2 + d3) * d4`. The parentheses of a function call don't make a group.
+    pub fn groups(&self, input: &str) -> Result<Vec<(String, i64)>, CalcError>
+    {
+        let expr = input.split_once('=').map_or(input, |(_, definition)| definition).trim();
+        let (_, expr) = split_output_base(expr, self.settings.implicit_base)?;
+
+        let mut groups = Vec::new();
+        for (open, close) in top_level_groups(expr)
+        {
+            let inner = &expr[open + 1..close];
+            let tokens = tokenize_with(inner, &self.settings)?;
+            let variables = self.scope(&tokens)?;
+            let tree = Expr::from_rpn(shunting_yard(tokens)?)?;
+            let evaluation = eval_ast(&tree, &variables, &self.settings, &|name, args| self.call(name, args, 0))?;
+            groups.push((format!("{}{}{}", &expr[open..=open], inner.trim(), &expr[close..=close]), evaluation.values[0]));
+        }
+        Ok(groups)
+    }
+
+    // Handles `table(x, lo, hi, expression) [base]`: the expression's value for each `x` from `lo` to `hi`,
+    // one step at a time. `x` takes the base of `lo`, and the values the output base, or else the base
+    // inferred from the expression as if `x` were a variable.
+    pub fn table(&self, input: &str) -> Result<(String, Vec<(Number, Number)>), CalcError>
+    {
+        let (output_base, expr) = split_output_base(input.trim(), self.settings.implicit_base)?;
+        let args = expr.strip_prefix("table(").and_then(|expr| expr.strip_suffix(')')).ok_or(CalcError::InvalidExpression)?;
+        let args = split_arguments(args).ok_or(CalcError::InvalidExpression)?;
+        let [name, low, high, body] = args[..]
+        else
+        {
+            return Err(CalcError::WrongArgumentCount("table".to_string(), 4, args.len()));
+        };
+        if !is_identifier(name)
+        {
+            return Err(invalid_name(name));
+        }
+
+        let bound = |text: &str| -> Result<Number, CalcError>
+        {
+            let tokens = tokenize_with(text, &self.settings)?;
+            let base = self.inferred_base(&tokens);
+            let variables = self.scope(&tokens)?;
+            let evaluation = eval_ast(&single_valued(Expr::from_rpn(shunting_yard(tokens)?)?)?, &variables, &self.settings, &|name, args| self.call(name, args, 0))?;
+            Ok(Number { value: evaluation.values[0], base })
+        };
+        let low = bound(low)?;
+        let high = bound(high)?;
+        if high.value < low.value || high.value.abs_diff(low.value) >= MAX_TABLE_ROWS as u64
+        {
+            return Err(CalcError::InvalidArgument("table".to_string(), high.value));
+        }
+
+        let tokens = tokenize_with(body, &self.settings)?;
+        let first_operand = tokens.iter().find_map(|token| match token
+        {
+            Token::Variable(var) if var == name => Some(low.base),
+            Token::Variable(var) => self.variable_base(var),
+            Token::Number(num) => Some(num.base),
+            _ => None,
+        });
+        let base = output_base.or(self.settings.output_base).or(first_operand).unwrap_or(Base::Decimal);
+        // Like a function's parameter, `x` hides a variable of the same name
+        let mut variables = self.scope(&tokens)?.into_owned();
+        let expr = single_valued(Expr::from_rpn(shunting_yard(tokens)?)?)?;
+
+        let mut rows = Vec::new();
+        for value in low.value..=high.value
+        {
+            let x = Number { value, base: low.base };
+            variables.insert(name.to_string(), x.clone());
+            let evaluation = eval_ast(&expr, &variables, &self.settings, &|name, args| self.call(name, args, 0))?;
+            rows.push((x, Number { value: evaluation.values[0], base }));
+        }
+        Ok((name.to_string(), rows))
+    }
+
+    // The byte offset in `input` of what made `eval` fail with `err`, when it was something the tokenizer
+    // can point at, such as a bad character or number.
+    pub fn error_offset(&self, input: &str, err: &CalcError) -> Option<usize>
+    {
+        let expr = input.split_once('=').map_or(input, |(_, definition)| definition).trim();
+        let (_, expr) = split_output_base(expr, self.settings.implicit_base).ok()?;
+        let offset = match tokenize_lenient_with(expr, &self.settings).1.into_iter().next()
+        {
+            Some((offset, first)) if first == *err => offset,
+            Some(_) => return None,
+            // The tokenizer takes any name before `(` as a function the session may know, so look for the call
+            None => match err
+            {
+                CalcError::UnknownFunction(name) => find_call(expr, name)?,
+                _ => return None,
+            },
+        };
+        // `expr` is a slice of `input`, so the distance between their starts is where it begins
+        Some(expr.as_ptr() as usize - input.as_ptr() as usize + offset)
+    }
+
+    // Evaluates a plain expression. The output base may be left out, in which case it is inferred like on an assignment.
+    fn evaluate(&self, input: &str) -> Result<EvalResult, CalcError>
+    {
+        let (output_base, expr) = split_output_base(input, self.settings.implicit_base)?;
+
+        let tokens = tokenize_with(expr, &self.settings)?;
+        let output_base = output_base.or(self.settings.output_base).unwrap_or_else(|| self.inferred_base(&tokens));
+        let literals = literals(&tokens);
+        let token_count = tokens.len();
+        let variables = self.scope(&tokens)?;
+
+        let expr = Expr::from_rpn(shunting_yard(tokens)?)?;
+        let labels = match &expr
+        {
+            Expr::Call(function, _) => function.labels(),
+            _ => &[],
+        };
+
+        let evaluation = eval_ast(&expr, &variables, &self.settings, &|name, args| self.call(name, args, 0))?;
+        let stats = EvalStats { tokens: token_count, operations: evaluation.operations };
+
+        let values = evaluation.values;
+        let number = Number { value: values[0], base: output_base };
+        let parts = match values.len()
+        {
+            1 => Vec::new(),
+            _ => labels.iter().zip(values).map(|(label, value)| (*label, Number { value, base: output_base })).collect(),
+        };
+        Ok(EvalResult { variable: None, number, parts, literals, stats })
+    }
+
+    // Handles `name = expr [base]`. Without an output base the variable takes the inferred one.
+    fn assign(&mut self, name: &str, definition: &str) -> Result<EvalResult, CalcError>
+    {
+        if !is_identifier(name)
+        {
+            return Err(invalid_name(name));
+        }
+
+        let (output_base, expr) = split_output_base(definition, self.settings.implicit_base)?;
+
+        let tokens = tokenize_with(expr, &self.settings)?;
+
+        let references = |wanted: &str| tokens.iter().any(|token| matches!(token, Token::Variable(var) if var == wanted));
+        if references(name) && !self.variables.contains_key(name)
+        {
+            return Err(CalcError::SelfReferentialVariable(name.to_string()));
+        }
+
+        let base = output_base.or(self.settings.output_base).unwrap_or_else(|| self.inferred_base(&tokens));
+        let literals = literals(&tokens);
+        let token_count = tokens.len();
+        let variables = self.scope(&tokens)?;
+
+        let expr = Expr::from_rpn(shunting_yard(tokens)?)?;
+        match &expr
+        {
+            // A variable holds a single value
+            Expr::Call(function, _) if function.labels().len() > 1 => return Err(CalcError::MultipleValues(function.name().to_string())),
+            _ => {}
+        }
+
+        let evaluation = eval_ast(&expr, &variables, &self.settings, &|name, args| self.call(name, args, 0))?;
+        let stats = EvalStats { tokens: token_count, operations: evaluation.operations };
+
+        let number = Number { value: evaluation.values[0], base };
+        self.define_var(name, number.clone())?;
+
+        Ok(EvalResult { variable: Some(name.to_string()), number, parts: Vec::new(), literals, stats })
+    }
+
+    // Runs the user function `name` on `args`, `depth` calls deep.
+    fn call(&self, name: &str, args: &[i64], depth: usize) -> Result<i64, CalcError>
+    {
+        let function = self.functions.get(name).ok_or_else(|| CalcError::UnknownFunction(name.to_string()))?;
+        if args.len() != function.params.len()
+        {
+            return Err(CalcError::WrongArgumentCount(name.to_string(), function.params.len(), args.len()));
+        }
+        if depth >= MAX_CALL_DEPTH
+        {
+            return Err(CalcError::RecursionLimit(name.to_string()));
+        }
+
+        // Parameters hide variables of the same name for the length of the call
+        let tokens = tokenize_with(&function.body, &self.settings)?;
+        let mut variables = self.scope(&tokens)?.into_owned();
+        for (param, value) in function.params.iter().zip(args)
+        {
+            variables.insert(param.clone(), Number { value: *value, base: Base::Decimal });
+        }
+
+        let expr = Expr::from_rpn(shunting_yard(tokens)?)?;
+        let evaluation = eval_ast(&expr, &variables, &self.settings, &|name, args| self.call(name, args, depth + 1))?;
+        Ok(evaluation.values[0])
+    }
+
+    // The variables `tokens` can see: the session's, plus `_` when they use it.
+    fn scope(&self, tokens: &[Token]) -> Result<Cow<'_, HashMap<String, Number>>, CalcError>
+    {
+        if !tokens.iter().any(|token| matches!(token, Token::Variable(var) if var == LAST_RESULT))
+        {
+            return Ok(Cow::Borrowed(&self.variables));
+        }
+        let last = self.last_result.clone().ok_or(CalcError::NoLastResult)?;
+        let mut variables = self.variables.clone();
+        variables.insert(LAST_RESULT.to_string(), last);
+        Ok(Cow::Owned(variables))
+    }
+
+    fn variable_base(&self, name: &str) -> Option<Base>
+    {
+        match name
+        {
+            LAST_RESULT => self.last_result.as_ref().map(|num| num.base),
+            _ => self.variables.get(name).map(|num| num.base),
+        }
+    }
+
+    // The output base when none is given: the base of the first operand, a variable or a literal (unprefixed ones
+    // count as the implicit base), so results stay in the base of the data they start from. `x + d1` follows `x`,
+    // `d1 + x` doesn't.
+    fn inferred_base(&self, tokens: &[Token]) -> Base
+    {
+        tokens.iter().find_map(|token| match token
+        {
+            Token::Variable(var) => self.variable_base(var),
+            Token::Number(num) => Some(num.base),
+            _ => None,
+        })
+        .unwrap_or(Base::Decimal)
+    }
+}
+
+// Byte offsets of the open and close paren of each group that isn't inside another one. A paren right
+// after a name is a call's, not a group's.
+fn top_level_groups(expr: &str) -> Vec<(usize, usize)>
+{
+    let mut groups = Vec::new();
+    let mut depth = 0usize;
+    let mut open = None;
+    let mut previous = None;
+
+    for (i, c) in expr.char_indices()
+    {
+        match c
+        {
+            '(' | '[' | '{' =>
+            {
+                if depth == 0 && !previous.is_some_and(|previous: char| previous.is_ascii_alphanumeric() || previous == '_')
+                {
+                    open = Some(i);
+                }
+                depth += 1;
+            }
+            ')' | ']' | '}' =>
+            {
+                depth = depth.saturating_sub(1);
+                if let Some(open) = open.filter(|_| depth == 0)
+                {
+                    groups.push((open, i));
+                }
+                if depth == 0
+                {
+                    open = None;
+                }
+            }
+            _ => {}
+        }
+        previous = Some(c);
+    }
+    groups
+}
+
+// `text` as a quoted JSON string. A number's text can hold any whitespace that groups its digits, like a tab.
+fn json_string(text: &str) -> String
+{
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars()
+    {
+        match c
+        {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Why `name` can't be a variable or parameter: a lone `h` is taken for the start of a hex number.
+fn invalid_name(name: &str) -> CalcError
+{
+    let mut chars = name.chars();
+    match (chars.next().map(Base::from_char), chars.next())
+    {
+        (Some(Ok(base)), None) => CalcError::BasePrefixName(base),
+        _ => CalcError::InvalidVariableName(name.to_string()),
+    }
+}
+
+// The tree as it is, unless it is a call giving several values, like `divmod`, where one is needed.
+fn single_valued(expr: Expr) -> Result<Expr, CalcError>
+{
+    match &expr
+    {
+        Expr::Call(function, _) if function.labels().len() > 1 => Err(CalcError::MultipleValues(function.name().to_string())),
+        _ => Ok(expr),
+    }
+}
+
+// Splits a call's arguments at the commas that aren't inside parentheses, trimmed. `None` if a `)` closes
+// the call before the end, as in `table(...) * (...)`.
+fn split_arguments(args: &str) -> Option<Vec<&str>>
+{
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+
+    for (i, c) in args.char_indices()
+    {
+        match c
+        {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 =>
+            {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(args[start..].trim());
+    Some(parts)
+}
+
+// Where `name(` first appears in `expr` as a whole name, not the end of a longer one.
+fn find_call(expr: &str, name: &str) -> Option<usize>
+{
+    let call = format!("{}(", name);
+    expr.match_indices(&call)
+        .map(|(offset, _)| offset)
+        .find(|&offset| !expr[..offset].ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+// Splits the optional output base off the end of an expression, written as a letter or as a word after `in`
+// or `as`. A trailing base letter is the output base when it stands on its own: after a space or a `)`, or
+// after a number it can't be a digit of (`d3d`). Ending a name (`x + speed`) it is part of the name. When it
+// could be the number's last digit (`hFFd`) it is an error, since reading it either way would silently
+// change the result.
+fn split_output_base(input: &str, implicit_base: Option<Base>) -> Result<(Option<Base>, &str), CalcError>
+{
+    // The base can also be spelled out after `in` or `as` (`d255 in hex`). Two words after an expression
+    // can't be anything else, so this never takes a variable called `hex` away
+    let spelled = input.rsplit_once(char::is_whitespace).and_then(|(rest, word)| Some((rest.trim_end().rsplit_once(char::is_whitespace)?, word)));
+    if let Some(((expr, "in" | "as"), word)) = spelled
+    {
+        let expr = expr.trim();
+        if expr.is_empty()
+        {
+            return Err(CalcError::EmptyExpression);
+        }
+        let base = Base::from_name(word).ok_or_else(|| CalcError::UnknownBaseName(word.to_string()))?;
+        return Ok((Some(base), expr));
+    }
+
+    let Some(base) = input.chars().last().and_then(|c| Base::from_char(c).ok())
+    else
+    {
+        return Ok((None, input));
+    };
+    let rest = &input[..input.len() - 1];
+
+    let word_start = rest.rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).map_or(0, |i| i + 1);
+    let word = &rest[word_start..];
+    if !word.is_empty()
+    {
+        let number_base = match implicit_base
+        {
+            Some(implicit) if is_implicit_number(word, implicit) => Some(implicit),
+            _ if is_number_literal(word) => Base::from_char(word.chars().next().unwrap()).ok(),
+            _ => None,
+        };
+        match number_base
+        {
+            Some(number_base) if base.prefix().is_digit(number_base.radix()) => return Err(CalcError::AmbiguousOutputBase),
+            Some(_) => {}
+            None => return Ok((None, input)),
+        }
+    }
+
+    let expr = rest.trim();
+    if expr.is_empty()
+    {
+        return Err(CalcError::EmptyExpression);
+    }
+    Ok((Some(base), expr))
+}
+
+fn literals(tokens: &[Token]) -> Vec<Number>
+{
+    tokens
+        .iter()
+        .filter_map(|token| match token
+        {
+            Token::Number(num) => Some(num.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn eval(session: &mut Session, input: &str) -> Result<String, CalcError>
+    {
+        session.eval(input).map(|result| result.to_string())
+    }
+
+    #[test]
+    fn assignments_build_on_each_other()
+    {
+        let mut session = Session::new();
+        assert_eq!(eval(&mut session, "x = hFF"), Ok("x = hFF".into()));
+        assert_eq!(eval(&mut session, "y = x + d5"), Ok("y = h104".into()));
+        assert_eq!(eval(&mut session, "z = y * d2 d"), Ok("z = d520".into()));
+        assert_eq!(session.variable("x"), Some(&Number { value: 255, base: Base::Hexadecimal }));
+        assert_eq!(session.variable("y"), Some(&Number { value: 260, base: Base::Hexadecimal }));
+        assert_eq!(session.variable("z"), Some(&Number { value: 520, base: Base::Decimal }));
+    }
+
+    #[test]
+    fn assignments_reject_bad_references()
+    {
+        let mut session = Session::new();
+        assert_eq!(eval(&mut session, "q = q + d1"), Err(CalcError::SelfReferentialVariable("q".into())));
+        assert_eq!(eval(&mut session, "y = nothing + d1"), Err(CalcError::UndefinedVariable("nothing".into())));
+        assert_eq!(session.variable("q"), None);
+        assert_eq!(session.variable("y"), None);
+    }
+
+    #[test]
+    fn output_base_spaced_or_unspaced()
+    {
+        let mut session = Session::new();
+        assert_eq!(eval(&mut session, "d5 + d3 h"), Ok("h8".into()));
+        assert_eq!(eval(&mut session, "d5 + d3h"), Ok("h8".into()));
+        assert_eq!(eval(&mut session, "(d1 + hF)d"), Ok("d16".into()));
+        assert_eq!(eval(&mut session, "hFF d"), Ok("d255".into()));
+        assert_eq!(eval(&mut session, "hFFd"), Err(CalcError::AmbiguousOutputBase));
+        // A letter ending a name is part of it
+        assert_eq!(eval(&mut session, "speed = d3"), Ok("speed = d3".into()));
+        assert_eq!(eval(&mut session, "d1 + speed"), Ok("d4".into()));
+    }
+
+    #[test]
+    fn negation_inside_parentheses()
+    {
+        let mut session = Session::new();
+        assert_eq!(eval(&mut session, "d10 * ( -d2 ) d"), Ok("d-20".into()));
+        assert_eq!(eval(&mut session, "((-(-(d3)))) d"), Ok("d3".into()));
+        assert_eq!(eval(&mut session, "(d5 * -d2) + d1 d"), Ok("d-9".into()));
+        assert_eq!(eval(&mut session, "d4 - (-d2 - -d1) d"), Ok("d5".into()));
+    }
+
+    #[test]
+    fn user_functions_with_several_parameters()
+    {
+        let mut session = Session::new();
+        assert_eq!(session.define_function("def double(n) = n * d2"), Ok("double(n)".into()));
+        assert_eq!(session.define_function("def field(reg, shift, mask) = (reg >> shift) & mask"), Ok("field(reg, shift, mask)".into()));
+        assert_eq!(eval(&mut session, "double(d21) d"), Ok("d42".into()));
+        assert_eq!(eval(&mut session, "field(hABCD, d4, hFF) h"), Ok("hBC".into()));
+        assert_eq!(eval(&mut session, "double(field(hABCD, d8, hF)) d"), Ok("d22".into()));
+
+        assert_eq!(eval(&mut session, "double(d1, d2)"), Err(CalcError::WrongArgumentCount("double".into(), 1, 2)));
+        assert_eq!(eval(&mut session, "field(hABCD, d4)"), Err(CalcError::WrongArgumentCount("field".into(), 3, 2)));
+    }
+
+    #[test]
+    fn user_function_definitions_reject_bad_names()
+    {
+        let mut session = Session::new();
+        assert_eq!(session.define_function("def f(h) = h + d1"), Err(CalcError::BasePrefixName(Base::Hexadecimal)));
+        assert_eq!(session.define_function("def f(x, d) = x"), Err(CalcError::BasePrefixName(Base::Decimal)));
+        assert_eq!(session.define_function("def f(x, x) = x"), Err(CalcError::InvalidDefinition));
+        assert_eq!(session.define_function("def f(1x) = d1"), Err(CalcError::InvalidVariableName("1x".into())));
+        assert_eq!(session.define_function("def width(x) = x"), Err(CalcError::BuiltinFunction("width".into())));
+        assert_eq!(session.define_function("def f(x) = x + y"), Err(CalcError::UndefinedVariable("y".into())));
+        assert_eq!(eval(&mut session, "b = d1"), Err(CalcError::BasePrefixName(Base::Binary)));
+        assert_eq!(CalcError::BasePrefixName(Base::Octal).to_string(), "Invalid name: o is the octal base prefix");
+    }
+
+    #[test]
+    fn a_host_keeps_state_in_an_eval_context()
+    {
+        let mut ctx = EvalContext::default();
+        ctx.settings_mut().width = 16;
+        let defined = eval_with("base = h1000", &mut ctx).unwrap();
+        assert_eq!(defined.variable.as_deref(), Some("base"));
+        assert_eq!(eval_with("base + h20", &mut ctx).unwrap().number, Number { value: 0x1020, base: Base::Hexadecimal });
+        assert_eq!(eval_with("base * d2 d", &mut ctx).unwrap().to_string(), "d8192");
+        assert_eq!(ctx.settings().width, 16);
+        assert_eq!(ctx.last_result(), Some(&Number { value: 8192, base: Base::Decimal }));
+    }
+
+    #[test]
+    fn a_minus_after_the_prefix_is_the_literal_sign()
+    {
+        let mut session = Session::new();
+        assert_eq!(eval(&mut session, "h-FF d"), Ok("d-255".into()));
+        assert_eq!(eval(&mut session, "b-10 d"), Ok("d-2".into()));
+        assert_eq!(eval(&mut session, "o-17 d"), Ok("d-15".into()));
+        assert_eq!(eval(&mut session, "d-5 + d1"), Ok("d-4".into()));
+        // The spaced `-` subtracts, the one after `h` belongs to the literal
+        assert_eq!(eval(&mut session, "d5 - h-FF d"), Ok("d260".into()));
+        assert_eq!(eval(&mut session, "d5-h-FF d"), Ok("d260".into()));
+        assert_eq!(eval(&mut session, "-h-FF d"), Ok("d255".into()));
+        assert_eq!(Number::parse("h-FF"), Ok(Number { value: -255, base: Base::Hexadecimal }));
+        assert_eq!(eval(&mut session, "h--FF d"), Err(CalcError::MissingDigits(Base::Hexadecimal)));
+    }
+
+    #[test]
+    fn explain_narrates_in_evaluation_order()
+    {
+        let mut session = Session::new();
+        assert_eq!(session.explain("d2 + d3 * d4").as_deref(), Ok("multiply d3 and d4 first (higher precedence) to get d12, then add d2 and d12 to get d14."));
+        assert_eq!(session.explain("(d8 - d2) / d3 h").as_deref(), Ok("subtract d2 from d8 first (in parentheses) to get h6, then divide h6 by d3 to get h2."));
+        assert_eq!(session.explain("-(d2 + d3)").as_deref(), Ok("add d2 and d3 first (in parentheses) to get d5, then negate d5 to get d-5."));
+        assert_eq!(session.explain("d5").as_deref(), Ok("there is nothing to work out: the value is d5."));
+        eval(&mut session, "x = d7").unwrap();
+        assert_eq!(session.explain("x - d1").as_deref(), Ok("subtract d1 from x (d7) to get d6."));
+    }
+
+    #[test]
+    fn output_base_spelled_out()
+    {
+        let mut session = Session::new();
+        assert_eq!(eval(&mut session, "d255 in hex"), Ok("hFF".into()));
+        assert_eq!(eval(&mut session, "d5 + d3 as binary"), Ok("b1000".into()));
+        assert_eq!(eval(&mut session, "hFF in d"), Ok("d255".into()));
+        assert_eq!(eval(&mut session, "d255 in foo"), Err(CalcError::UnknownBaseName("foo".into())));
+        assert_eq!(eval(&mut session, "d255 as Hex"), Err(CalcError::UnknownBaseName("Hex".into())));
+        assert_eq!(eval(&mut session, "in hex"), Err(CalcError::InvalidExpression));
+        // A variable named like a base is still a variable
+        assert_eq!(eval(&mut session, "hex = d3"), Ok("hex = d3".into()));
+        assert_eq!(eval(&mut session, "hex + d1 in oct"), Ok("o4".into()));
+    }
+
+    #[test]
+    fn dump_tokens_as_in_the_readme()
+    {
+        let mut session = Session::new();
+        assert_eq!(
+            session.dump_tokens("hFF + x").as_deref(),
+            Ok(r#"[{"kind":"number","value":255,"base":"hexadecimal","text":"hFF","start":0,"end":3},{"kind":"operator","symbol":"+","unary":false,"text":"+","start":4,"end":5},{"kind":"variable","name":"x","text":"x","start":6,"end":7}]"#)
+        );
+        assert_eq!(
+            session.dump_tokens("-(d2) d").as_deref(),
+            Ok(r#"[{"kind":"operator","symbol":"-","unary":true,"text":"-","start":0,"end":1},{"kind":"open_paren","text":"(","start":1,"end":2},{"kind":"number","value":2,"base":"decimal","text":"d2","start":2,"end":4},{"kind":"close_paren","text":")","start":4,"end":5}]"#)
+        );
+        // A tab grouping digits is escaped, and spans count from the start of the input
+        assert_eq!(
+            session.dump_tokens("  h DE\tAD").as_deref(),
+            Ok(r#"[{"kind":"number","value":57005,"base":"hexadecimal","text":"h DE\tAD","start":2,"end":9}]"#)
+        );
+        session.settings_mut().unsigned = true;
+        assert_eq!(
+            session.dump_tokens("hFFFFFFFFFFFFFFFF").as_deref(),
+            Ok(r#"[{"kind":"number","value":18446744073709551615,"base":"hexadecimal","text":"hFFFFFFFFFFFFFFFF","start":0,"end":17}]"#)
+        );
+        assert_eq!(session.dump_tokens("d1 $"), Err(CalcError::InvalidCharacter('$')));
+    }
+
+    #[test]
+    fn output_base_follows_the_first_operand()
+    {
+        let mut session = Session::new();
+        eval(&mut session, "x = hFF").unwrap();
+        eval(&mut session, "y = b101").unwrap();
+        assert_eq!(eval(&mut session, "x + d1"), Ok("h100".into()));
+        assert_eq!(eval(&mut session, "(x + d1) * d2"), Ok("h200".into()));
+        assert_eq!(eval(&mut session, "-x"), Ok("h-FF".into()));
+        assert_eq!(eval(&mut session, "y + x"), Ok("b100000100".into()));
+        // A literal first keeps its own base
+        assert_eq!(eval(&mut session, "d1 + x"), Ok("d256".into()));
+        assert_eq!(eval(&mut session, "o10 * x"), Ok("o3770".into()));
+        assert_eq!(eval(&mut session, "x + d1 d"), Ok("d256".into()));
+    }
+
+    #[test]
+    fn recursion_stops_at_the_call_depth_limit()
+    {
+        let mut session = Session::new();
+        session.define_function("def fact(n) = n < d2 ? d1 : n * fact(n - d1)").unwrap();
+        assert_eq!(eval(&mut session, "fact(d10) d"), Ok("d3628800".into()));
+        session.define_function("def forever(n) = forever(n + d1)").unwrap();
+        assert_eq!(eval(&mut session, "forever(d0)"), Err(CalcError::RecursionLimit("forever".into())));
+        session.define_function("def down(n) = n < d1 ? d0 : down(n - d1)").unwrap();
+        assert_eq!(eval(&mut session, "down(d60) d"), Ok("d0".into()));
+        assert_eq!(eval(&mut session, "down(d100)"), Err(CalcError::RecursionLimit("down".into())));
+    }
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use crate::number::Base;
+use crate::token::Operation;
+
+// How the evaluator behaves. Where languages disagree, `Default` matches Rust (and C).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings
+{
+    pub mod_style: ModStyle,
+    pub div_round: DivRound,
+    pub negative_shift: NegativeShift,
+    pub overflow: OverflowMode,
+    // Read every value as an unsigned 64-bit integer, from 0 to u64::MAX, instead of a signed one
+    pub unsigned: bool,
+    // Base of numbers written without a prefix. None requires a prefix on every number.
+    pub implicit_base: Option<Base>,
+    // Take commas between groups of three digits as part of a decimal number, as pasted from a spreadsheet (`d1,234,567`)
+    pub accept_commas: bool,
+    // Base of results that don't name one. None infers it from the expression.
+    pub output_base: Option<Base>,
+    // Longest input `Session::eval` accepts, in bytes, so a stray multi-megabyte paste fails fast
+    pub max_input_len: usize,
+    // Bit width of the integer a result is inspected as, one of `WIDTHS`
+    pub width: u32,
+    // How long evaluating one expression may take. None waits as long as it takes.
+    pub timeout: Option<Duration>,
+    // Operators that may not be used, for teaching or sandboxing. Disabling `-` disables negation too.
+    pub disabled_ops: Vec<Operation>,
+}
+
+pub const WIDTHS: [u32; 4] = [8, 16, 32, 64];
+
+pub const DEFAULT_MAX_INPUT_LEN: usize = 64 * 1024;
+
+impl Default for Settings
+{
+    fn default() -> Self
+    {
+        Settings
+        {
+            mod_style: ModStyle::default(),
+            div_round: DivRound::default(),
+            negative_shift: NegativeShift::default(),
+            overflow: OverflowMode::default(),
+            unsigned: false,
+            implicit_base: None,
+            accept_commas: false,
+            output_base: None,
+            max_input_len: DEFAULT_MAX_INPUT_LEN,
+            width: 64,
+            timeout: None,
+            disabled_ops: Vec::new(),
+        }
+    }
+}
+
+// Sign of the remainder when an operand of `%` is negative.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModStyle
+{
+    // Takes the sign of the dividend: `d-7 % d3` is `d-1`, like Rust and C
+    #[default]
+    Truncated,
+    // Takes the sign of the divisor: `d-7 % d3` is `d2`, like Python
+    Floored,
+}
+
+impl ModStyle
+{
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "trunc" => Some(ModStyle::Truncated),
+            "floor" => Some(ModStyle::Floored),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            ModStyle::Truncated => "trunc",
+            ModStyle::Floored => "floor",
+        }
+    }
+}
+
+// Which way `/` rounds a quotient that isn't whole.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DivRound
+{
+    // Toward zero: `d-7 / d2` is `d-3`, like Rust and C
+    #[default]
+    Truncated,
+    // Toward negative infinity: `d-7 / d2` is `d-4`, like Python's `//`
+    Floored,
+}
+
+impl DivRound
+{
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "trunc" => Some(DivRound::Truncated),
+            "floor" => Some(DivRound::Floored),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            DivRound::Truncated => "trunc",
+            DivRound::Floored => "floor",
+        }
+    }
+}
+
+// What a shift by a negative amount does.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum NegativeShift
+{
+    // It is an error, as in most languages
+    #[default]
+    Error,
+    // It shifts the other way: `d1 << d-2` is `d1 >> d2`
+    Reverse,
+}
+
+impl NegativeShift
+{
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "error" => Some(NegativeShift::Error),
+            "reverse" => Some(NegativeShift::Reverse),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            NegativeShift::Error => "error",
+            NegativeShift::Reverse => "reverse",
+        }
+    }
+}
+
+// What `+`, `-`, `*` and `/` do when the result doesn't fit in an i64 (or a u64, when unsigned).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OverflowMode
+{
+    // It is an error, like Rust's debug builds
+    #[default]
+    Checked,
+    // It wraps around in two's complement, like Rust's `wrapping_*` and release builds
+    Wrapping,
+    // It stops at the smallest or largest value, like Rust's `saturating_*`
+    Saturating,
+}
+
+impl OverflowMode
+{
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "checked" => Some(OverflowMode::Checked),
+            "wrap" => Some(OverflowMode::Wrapping),
+            "saturate" => Some(OverflowMode::Saturating),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            OverflowMode::Checked => "checked",
+            OverflowMode::Wrapping => "wrap",
+            OverflowMode::Saturating => "saturate",
+        }
+    }
+}
@@ -0,0 +1,1052 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::error::CalcError;
+use crate::function::Function;
+use crate::number::{Base, Number};
+use crate::settings::{DivRound, ModStyle, NegativeShift, OverflowMode, Settings};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation 
+{
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    ShiftLeft,
+    ShiftRight,
+    Less,
+    Greater,
+    BitAnd,
+    BitXor,
+    BitOr,
+    // Unary minus, as in `-x` or `d10 * (-d2)`
+    Negate,
+    // The `?` of `cond ? a : b`. In RPN it stands for the whole conditional, after its three operands.
+    Conditional,
+    OpenParen,
+    CloseParen,
+}
+
+// Which side of a chain of equal-precedence operators is worked out first: `d8 - d4 - d2` is `(d8 - d4) - d2`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Associativity
+{
+    Left,
+    Right,
+}
+
+// What there is to know about an operator, for help text and editors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpInfo
+{
+    pub operation: Operation,
+    pub symbol: &'static str,
+    // Spelled out instead of the symbol, like `and` for `&`
+    pub word: Option<&'static str>,
+    // Higher binds tighter
+    pub precedence: u8,
+    pub associativity: Associativity,
+    // How many operands it takes: 1 for negation, 3 for `?:` and 2 for the rest
+    pub arity: usize,
+}
+
+// Every operator, tightest-binding first. Parentheses group rather than operate, so they aren't listed.
+pub fn operators() -> Vec<OpInfo>
+{
+    let mut operators: Vec<OpInfo> = Operation::ALL
+        .into_iter()
+        .filter(|op| !matches!(op, Operation::OpenParen | Operation::CloseParen))
+        .map(|op| OpInfo
+        {
+            operation: op,
+            symbol: op.symbol(),
+            word: op.word(),
+            precedence: op.precedence(),
+            associativity: if matches!(op, Operation::Negate | Operation::Conditional) { Associativity::Right } else { Associativity::Left },
+            arity: match op
+            {
+                Operation::Negate => 1,
+                Operation::Conditional => 3,
+                _ => 2,
+            },
+        })
+        .collect();
+    operators.sort_by_key(|info| std::cmp::Reverse(info.precedence));
+    operators
+}
+
+impl Operation 
+{
+    pub const ALL: [Operation; 16] = [
+        Operation::Add,
+        Operation::Subtract,
+        Operation::Multiply,
+        Operation::Divide,
+        Operation::Modulo,
+        Operation::ShiftLeft,
+        Operation::ShiftRight,
+        Operation::Less,
+        Operation::Greater,
+        Operation::BitAnd,
+        Operation::BitXor,
+        Operation::BitOr,
+        Operation::Negate,
+        Operation::Conditional,
+        Operation::OpenParen,
+        Operation::CloseParen,
+    ];
+
+    pub fn precedence(&self) -> u8
+    {
+        match self 
+        {
+            // Same order as C
+            Operation::Conditional => 1,
+            Operation::BitOr => 2,
+            Operation::BitXor => 3,
+            Operation::BitAnd => 4,
+            Operation::Less | Operation::Greater => 5,
+            Operation::ShiftLeft | Operation::ShiftRight => 6,
+            Operation::Add | Operation::Subtract => 7,
+            Operation::Multiply | Operation::Divide | Operation::Modulo => 8,
+            Operation::Negate => 9,
+            Operation::OpenParen | Operation::CloseParen => 0,
+        }
+    }
+
+    pub fn from_char(c: char) -> Result<Self, CalcError> 
+    {
+        match c 
+        {
+            '+' => Ok(Operation::Add),
+            '-' => Ok(Operation::Subtract),
+            '*' => Ok(Operation::Multiply),
+            '/' => Ok(Operation::Divide),
+            '%' => Ok(Operation::Modulo),
+            '<' => Ok(Operation::Less),
+            '>' => Ok(Operation::Greater),
+            '&' => Ok(Operation::BitAnd),
+            '^' => Ok(Operation::BitXor),
+            '|' => Ok(Operation::BitOr),
+            '?' => Ok(Operation::Conditional),
+            '(' => Ok(Operation::OpenParen),
+            ')' => Ok(Operation::CloseParen),
+            _ => Err(CalcError::InvalidOperator(c)),
+        }
+    }
+
+    // How the operator is written. Negation shares `-` with subtraction.
+    pub fn symbol(&self) -> &'static str
+    {
+        match self
+        {
+            Operation::Add => "+",
+            Operation::Subtract | Operation::Negate => "-",
+            Operation::Multiply => "*",
+            Operation::Divide => "/",
+            Operation::Modulo => "%",
+            Operation::ShiftLeft => "<<",
+            Operation::ShiftRight => ">>",
+            Operation::Less => "<",
+            Operation::Greater => ">",
+            Operation::BitAnd => "&",
+            Operation::BitXor => "^",
+            Operation::BitOr => "|",
+            Operation::Conditional => "?",
+            Operation::OpenParen => "(",
+            Operation::CloseParen => ")",
+        }
+    }
+
+    // Word spellings of operators, for shells where `<`, `&`, `|` and friends need quoting.
+    // They are reserved: a variable can't be named after one.
+    pub fn word(&self) -> Option<&'static str>
+    {
+        match self
+        {
+            Operation::BitAnd => Some("and"),
+            Operation::BitOr => Some("or"),
+            Operation::BitXor => Some("xor"),
+            Operation::ShiftLeft => Some("shl"),
+            Operation::ShiftRight => Some("shr"),
+            Operation::Less => Some("lt"),
+            Operation::Greater => Some("gt"),
+            Operation::Modulo => Some("mod"),
+            _ => None,
+        }
+    }
+
+    pub fn from_word(word: &str) -> Option<Self>
+    {
+        Operation::ALL.into_iter().find(|op| op.word() == Some(word))
+    }
+
+    // An operator named by its symbol or its word, as in a list of operators to disable. `-` is subtraction.
+    pub fn from_symbol(symbol: &str) -> Option<Self>
+    {
+        Operation::ALL.into_iter().find(|op| op.symbol() == symbol).or_else(|| Operation::from_word(symbol))
+    }
+
+    pub fn apply(&self, left: i64, right: i64, settings: &Settings) -> Result<i64, CalcError> 
+    {
+        if settings.unsigned
+        {
+            return self.apply_unsigned(left as u64, right as u64, settings).map(|value| value as i64);
+        }
+        match self 
+        {
+            Operation::Add => overflowing(left.checked_add(right), left.wrapping_add(right), left.saturating_add(right), "addition", settings),
+            Operation::Subtract => overflowing(left.checked_sub(right), left.wrapping_sub(right), left.saturating_sub(right), "subtraction", settings),
+            Operation::Multiply => overflowing(left.checked_mul(right), left.wrapping_mul(right), left.saturating_mul(right), "multiplication", settings),
+            Operation::Less => Ok((left < right) as i64),
+            Operation::Greater => Ok((left > right) as i64),
+            Operation::BitAnd => Ok(left & right),
+            Operation::BitXor => Ok(left ^ right),
+            Operation::BitOr => Ok(left | right),
+            Operation::Divide => 
+            {
+                if right == 0 
+                {
+                    return Err(CalcError::DivisionByZero);
+                }
+                // i64::MIN / -1 is the one quotient that doesn't fit
+                let quotient = overflowing(left.checked_div(right), left.wrapping_div(right), left.saturating_div(right), "division", settings)?;
+                match settings.div_round 
+                {
+                    DivRound::Floored if left.wrapping_rem(right) != 0 && (left < 0) != (right < 0) => Ok(quotient - 1),
+                    _ => Ok(quotient),
+                }
+            }
+            Operation::Modulo => 
+            {
+                if right == 0 
+                {
+                    return Err(CalcError::DivisionByZero);
+                }
+                // Wrapping only matters for i64::MIN % -1, whose remainder is 0 anyway
+                let remainder = left.wrapping_rem(right);
+                match settings.mod_style 
+                {
+                    ModStyle::Floored if remainder != 0 && (remainder < 0) != (right < 0) => Ok(remainder + right),
+                    _ => Ok(remainder),
+                }
+            }
+            Operation::ShiftLeft | Operation::ShiftRight => 
+            {
+                // In reverse mode `x << -n` is `x >> n`, and the other way around
+                let reversed = right < 0 && settings.negative_shift == NegativeShift::Reverse;
+                let amount = if reversed { u32::try_from(right.unsigned_abs()).ok() } else { u32::try_from(right).ok() };
+                let amount = amount.filter(|amount| *amount < i64::BITS).ok_or(CalcError::InvalidShiftAmount(right))?;
+                match (self, reversed) 
+                {
+                    (Operation::ShiftLeft, false) | (Operation::ShiftRight, true) => Ok(left << amount),
+                    _ => Ok(left >> amount),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // `apply` on values read as u64. Division and remainder have no negative operands to round, and `>>` shifts in zeros.
+    fn apply_unsigned(&self, left: u64, right: u64, settings: &Settings) -> Result<u64, CalcError> 
+    {
+        match self 
+        {
+            Operation::Add => overflowing(left.checked_add(right), left.wrapping_add(right), left.saturating_add(right), "addition", settings),
+            Operation::Subtract => overflowing(left.checked_sub(right), left.wrapping_sub(right), left.saturating_sub(right), "subtraction", settings),
+            Operation::Multiply => overflowing(left.checked_mul(right), left.wrapping_mul(right), left.saturating_mul(right), "multiplication", settings),
+            Operation::Less => Ok((left < right) as u64),
+            Operation::Greater => Ok((left > right) as u64),
+            Operation::BitAnd => Ok(left & right),
+            Operation::BitXor => Ok(left ^ right),
+            Operation::BitOr => Ok(left | right),
+            Operation::Divide | Operation::Modulo if right == 0 => Err(CalcError::DivisionByZero),
+            Operation::Divide => Ok(left / right),
+            Operation::Modulo => Ok(left % right),
+            Operation::ShiftLeft | Operation::ShiftRight => 
+            {
+                let amount = u32::try_from(right).ok().filter(|amount| *amount < u64::BITS).ok_or(CalcError::InvalidShiftAmount(right as i64))?;
+                match self 
+                {
+                    Operation::ShiftLeft => Ok(left << amount),
+                    _ => Ok(left >> amount),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token 
+{
+    Number(Number),
+    Variable(String),
+    Operation(Operation),
+    // A name directly followed by `(`
+    Function(Function),
+    // A function applied to this many arguments, as `shunting_yard` writes it out
+    Call(Function, usize),
+    // Any other name directly followed by `(`: one the session defined with `def`, it is hoped
+    UserFunction(String),
+    // A user function applied to this many arguments
+    UserCall(String, usize),
+    // Separates a function's arguments
+    Comma,
+    // Between the two branches of `cond ? a : b`
+    Colon,
+}
+
+// Picks the result `settings.overflow` asks for, given what the checked, wrapping and saturating versions
+// of an operation came to.
+fn overflowing<T>(checked: Option<T>, wrapped: T, saturated: T, operation: &str, settings: &Settings) -> Result<T, CalcError>
+{
+    match settings.overflow
+    {
+        OverflowMode::Checked => checked.ok_or_else(|| CalcError::Overflow(operation.to_string())),
+        OverflowMode::Wrapping => Ok(wrapped),
+        OverflowMode::Saturating => Ok(saturated),
+    }
+}
+
+// Tokens as they would be typed. Calls are shown by their function's name, like the `Function` they came from.
+impl fmt::Display for Token
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Token::Number(num) => write!(f, "{}", num),
+            Token::Variable(name) | Token::UserFunction(name) | Token::UserCall(name, _) => write!(f, "{}", name),
+            Token::Operation(op) => write!(f, "{}", op.symbol()),
+            Token::Function(function) | Token::Call(function, _) => write!(f, "{}", function.name()),
+            Token::Comma => write!(f, ","),
+            Token::Colon => write!(f, ":"),
+        }
+    }
+}
+
+pub fn is_base_prefix(c: char) -> bool
+{
+    Base::from_char(c).is_ok()
+}
+
+// A base prefix followed only by hex digits is a number, even when it reads like a word (`hBAD`).
+pub fn is_number_literal(word: &str) -> bool
+{
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if is_base_prefix(c)) && is_digit_run(chars.as_str())
+}
+
+// Hex digits, optionally grouped with `_` (`0000_00FF`), as long as no separator comes first.
+fn is_digit_run(digits: &str) -> bool
+{
+    !digits.starts_with('_') && digits.chars().all(|c| c.is_ascii_hexdigit() || c == '_')
+}
+
+// A base prefix and hex digits with a `_` straight after the prefix (`d_5`): a number with a misplaced separator,
+// for `Number::parse` to reject, rather than a name. Names like `h_count` have letters that aren't hex digits.
+fn is_separated_literal(word: &str) -> bool
+{
+    let mut chars = word.chars();
+    let digits = match chars.next()
+    {
+        Some(c) if is_base_prefix(c) => chars.as_str(),
+        _ => return false,
+    };
+    digits.starts_with('_') && digits.contains(|c: char| c.is_ascii_hexdigit()) && digits.chars().all(|c| c.is_ascii_hexdigit() || c == '_')
+}
+
+// The assembler-style spelling of a number, with the base letter last (`FFh`, `1010b`, `377o`, `255d`), in the usual
+// prefix form, or `None` if the word isn't one. The digits must all be valid in that base, with hex letters in
+// uppercase: that keeps lowercase words such as `each` (`eac` + `h`) free for variable names. A word starting
+// with a digit can't be a name, so it is taken as one regardless and `12b` is reported as a bad binary number.
+pub fn suffix_literal(word: &str) -> Option<String>
+{
+    let suffix = word.chars().last()?;
+    let base = Base::from_char(suffix).ok()?;
+    let digits = &word[..word.len() - suffix.len_utf8()];
+    let valid = |c: char| c == '_' || (c.is_digit(base.radix()) && !c.is_ascii_lowercase());
+    let literal = digits.starts_with(|c: char| c.is_ascii_digit()) || (!digits.is_empty() && !digits.starts_with('_') && digits.chars().all(valid));
+    literal.then(|| format!("{}{}", suffix, digits))
+}
+
+// A decimal literal with its digits grouped by commas, like `d1,234,567`, without the commas. The first group has
+// one to three digits and every later one exactly three, so a comma in the wrong place isn't simply dropped.
+fn strip_commas(literal: &str) -> Option<String>
+{
+    let digits = literal.strip_prefix('d')?;
+    let (sign, digits) = digits.strip_prefix('-').map_or(("", digits), |digits| ("-", digits));
+    let mut groups = digits.split(',');
+    let first = groups.next()?;
+    let valid = |group: &str, len: std::ops::RangeInclusive<usize>| len.contains(&group.len()) && group.chars().all(|c| c.is_ascii_digit());
+    if !valid(first, 1..=3) || !groups.clone().all(|group| valid(group, 3..=3))
+    {
+        return None;
+    }
+    Some(format!("d{}{}{}", sign, first, groups.collect::<String>()))
+}
+
+pub fn is_identifier(word: &str) -> bool
+{
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !is_number_literal(word)
+        && !is_separated_literal(word)
+        && suffix_literal(word).is_none()
+        && Operation::from_word(word).is_none()
+}
+
+// Whether a word after a space carries on the number before it (`h DEAD BEEF`) instead of starting a new token.
+// A base prefix only ever begins a number, so inside one a letter that is a hex digit is a digit even when it
+// is also a prefix (`h dead beef`). The one exception is a lone prefix letter, which is the output base (`hA b`).
+pub fn is_digit_group(word: &str) -> bool
+{
+    !word.is_empty()
+        && is_digit_run(word)
+        && !(word.len() == 1 && word.starts_with(is_base_prefix))
+        && Operation::from_word(word).is_none()
+}
+
+// With an implicit base, a word starting with a digit is a number in that base. In hexadecimal so is a word of
+// uppercase hex digits (`FF`); lowercase letters are still base prefixes, so `dA` stays decimal.
+pub fn is_implicit_number(word: &str, base: Base) -> bool
+{
+    let uppercase_hex = |c: char| c.is_ascii_digit() || ('A'..='F').contains(&c) || c == '_';
+    word.starts_with(|c: char| c.is_ascii_digit())
+        || (base == Base::Hexadecimal && !word.is_empty() && !word.starts_with('_') && word.chars().all(uppercase_hex))
+}
+
+pub fn tokenize(expr: &str) -> Result<Vec<Token>, CalcError> 
+{
+    tokenize_with_base(expr, None)
+}
+
+// Tokenizes with numbers that have no base prefix read in `implicit_base`, if there is one.
+pub fn tokenize_with_base(expr: &str, implicit_base: Option<Base>) -> Result<Vec<Token>, CalcError> 
+{
+    tokenize_with(expr, &Settings { implicit_base, ..Settings::default() })
+}
+
+// Tokenizes the way `settings` reads numbers: its implicit base, and literals up to u64::MAX when unsigned.
+pub fn tokenize_with(expr: &str, settings: &Settings) -> Result<Vec<Token>, CalcError> 
+{
+    let (tokens, errors) = scan(expr, settings, false);
+    match errors.into_iter().next() 
+    {
+        Some((_, err)) => Err(err),
+        None => Ok(tokens),
+    }
+}
+
+// Like `tokenize_with`, with the byte range of `expr` each token was read from. A number's range covers its
+// prefix, sign and any grouping (`h DEAD BEEF` is one token).
+pub fn tokenize_spans(expr: &str, settings: &Settings) -> Result<Vec<(Token, Range<usize>)>, CalcError> 
+{
+    let mut tokens = Vec::new();
+    let errors = scan_into(expr, settings, false, &mut |token, span| 
+    {
+        tokens.push((token, span));
+        Ok(())
+    });
+    match errors.into_iter().next() 
+    {
+        Some((_, err)) => Err(err),
+        None => Ok(tokens),
+    }
+}
+
+// Never fails: everything that would make `tokenize` fail is left out of the tokens and reported with the
+// byte offset where it starts, and scanning carries on. For editors working on half-typed input.
+pub fn tokenize_lenient(expr: &str) -> (Vec<Token>, Vec<(usize, CalcError)>) 
+{
+    tokenize_lenient_with_base(expr, None)
+}
+
+// `tokenize_lenient` with numbers that have no base prefix read in `implicit_base`, if there is one.
+pub fn tokenize_lenient_with_base(expr: &str, implicit_base: Option<Base>) -> (Vec<Token>, Vec<(usize, CalcError)>) 
+{
+    tokenize_lenient_with(expr, &Settings { implicit_base, ..Settings::default() })
+}
+
+// `tokenize_lenient` reading numbers the way `settings` does, like `tokenize_with`.
+pub fn tokenize_lenient_with(expr: &str, settings: &Settings) -> (Vec<Token>, Vec<(usize, CalcError)>) 
+{
+    scan(expr, settings, true)
+}
+
+// A token and the byte range of the input it was read from.
+pub type Spanned = (Token, Range<usize>);
+
+// `tokenize_lenient_with` keeping each token's byte range, like `tokenize_spans`. Whatever no token covers
+// is either whitespace or something that was reported.
+pub fn tokenize_spans_lenient(expr: &str, settings: &Settings) -> (Vec<Spanned>, Vec<(usize, CalcError)>) 
+{
+    let mut tokens = Vec::new();
+    let errors = scan_into(expr, settings, true, &mut |token, span| 
+    {
+        tokens.push((token, span));
+        Ok(())
+    });
+    (tokens, errors)
+}
+
+// Where the tokens found so far went and the number being read, if any.
+struct Scan<'a> 
+{
+    emit: &'a mut dyn FnMut(Token, Range<usize>) -> Result<(), CalcError>,
+    // A `-` read now would negate: nothing it could subtract from has come yet
+    negates: bool,
+    errors: Vec<(usize, CalcError)>,
+    current_number: String,
+    // Byte offsets of the number's first character and just past its last
+    number_start: usize,
+    number_end: usize,
+    unsigned: bool,
+    disabled_ops: &'a [Operation],
+}
+
+impl Scan<'_> 
+{
+    fn push(&mut self, span: Range<usize>, token: Token) 
+    {
+        match &token 
+        {
+            // Negation is written with the same `-` as subtraction, so it goes with it
+            Token::Operation(op) if self.disabled_ops.iter().any(|disabled| disabled.symbol() == op.symbol()) => 
+            {
+                self.errors.push((span.start, CalcError::DisabledOperator(op.symbol())));
+                return;
+            }
+            _ => {}
+        }
+        // A minus with no operand before it (at the start, after an operator, an open paren or a comma) negates
+        self.negates = match &token 
+        {
+            Token::Comma | Token::Colon | Token::Function(_) | Token::UserFunction(_) => true,
+            Token::Operation(op) => *op != Operation::CloseParen,
+            _ => false,
+        };
+        let offset = span.start;
+        if let Err(err) = (self.emit)(token, span) 
+        {
+            self.errors.push((offset, err));
+        }
+    }
+
+    // Every kind of bracket is read as a parenthesis, but a disabled one is reported as it was typed
+    fn push_bracket(&mut self, offset: usize, c: char, op: Operation) 
+    {
+        if self.disabled_ops.contains(&op) 
+        {
+            let typed = match c 
+            {
+                '[' => "[",
+                ']' => "]",
+                '{' => "{",
+                '}' => "}",
+                _ => op.symbol(),
+            };
+            self.errors.push((offset, CalcError::DisabledOperator(typed)));
+            return;
+        }
+        self.push(offset..offset + 1, Token::Operation(op));
+    }
+
+    fn start_number(&mut self, offset: usize) 
+    {
+        if self.current_number.is_empty() 
+        {
+            self.number_start = offset;
+        }
+    }
+
+    fn finish_number(&mut self) 
+    {
+        if self.current_number.is_empty() 
+        {
+            return;
+        }
+        let parsed = if self.current_number.contains(',') 
+        {
+            strip_commas(&self.current_number)
+                .ok_or_else(|| CalcError::InvalidNumber(Base::Decimal, self.current_number[1..].to_string()))
+                .and_then(|literal| Number::parse_as(&literal, self.unsigned))
+        }
+        else 
+        {
+            Number::parse_as(&self.current_number, self.unsigned)
+        };
+        match parsed 
+        {
+            Ok(num) => self.push(self.number_start..self.number_end, Token::Number(num)),
+            Err(err) => self.errors.push((self.number_start, err)),
+        }
+        self.current_number.clear();
+    }
+}
+
+pub fn closing_bracket(open: char) -> char
+{
+    match open
+    {
+        '[' => ']',
+        '{' => '}',
+        _ => ')',
+    }
+}
+
+fn scan(expr: &str, settings: &Settings, lenient: bool) -> (Vec<Token>, Vec<(usize, CalcError)>) 
+{
+    let mut tokens = Vec::new();
+    let errors = scan_into(expr, settings, lenient, &mut |token, _| 
+    {
+        tokens.push(token);
+        Ok(())
+    });
+    (tokens, errors)
+}
+
+// Shared by the strict and lenient tokenizers and the streaming evaluator, which takes each token through `emit`
+// as soon as it is read. The strict ones stop at the first error, whether from the input or from `emit`.
+pub(crate) fn scan_into(expr: &str, settings: &Settings, lenient: bool, emit: &mut dyn FnMut(Token, Range<usize>) -> Result<(), CalcError>) -> Vec<(usize, CalcError)> 
+{
+    let implicit_base = settings.implicit_base;
+    let mut scan = Scan { emit, negates: true, errors: Vec::new(), current_number: String::new(), number_start: 0, number_end: 0, unsigned: settings.unsigned, disabled_ops: &settings.disabled_ops };
+
+    // Read one character at a time; anything further ahead is looked at in `expr` itself
+    let mut chars = expr.char_indices().peekable();
+    // `[]` and `{}` group like `()`, so each kind has to be closed by its own. Each is kept with whether it
+    // opened a call's arguments, where a comma always separates
+    let mut open_brackets: Vec<(char, bool)> = Vec::new();
+    let mut call = false;
+
+    while lenient || scan.errors.is_empty() 
+    {
+        let Some((offset, c)) = chars.next() else { break };
+        match c {
+            c if c.is_whitespace() => 
+            {
+                // Spaces inside a digit run are grouping (`h DEAD BEEF`), anywhere else they end the number
+                let next_word: String = expr[offset..]
+                    .chars()
+                    .skip_while(|c| c.is_whitespace())
+                    .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                    .collect();
+                if !is_digit_group(&next_word) 
+                {
+                    scan.finish_number();
+                }
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => 
+            {
+                while chars.next_if(|&(_, next)| next.is_ascii_alphanumeric() || next == '_').is_some() {}
+                let end = chars.peek().map_or(expr.len(), |&(next, _)| next);
+                let word = &expr[offset..end];
+
+                // After a sign or a grouping space the word is more digits of the current number
+                if !scan.current_number.is_empty() 
+                {
+                    scan.current_number.push_str(word);
+                }
+                // A lone base letter ending a call's argument is that argument's output base, written out of habit
+                // (`assert(d2 + d2 d, d4)`). An argument is only a value, so it is left out
+                else if word.len() == 1
+                    && word.starts_with(is_base_prefix)
+                    && open_brackets.last().is_some_and(|&(_, call)| call)
+                    && matches!(expr[end..].chars().find(|c| !c.is_whitespace()), Some(',' | ')')) 
+                {
+                }
+                else if let Some(op) = Operation::from_word(word) 
+                {
+                    scan.push(offset..end, Token::Operation(op));
+                }
+                else if expr[end..].starts_with('(') 
+                {
+                    call = true;
+                    match Function::from_name(word) 
+                    {
+                        Some(function) => scan.push(offset..end, Token::Function(function)),
+                        None if is_identifier(word) => scan.push(offset..end, Token::UserFunction(word.to_string())),
+                        None => scan.errors.push((offset, CalcError::UnknownFunction(word.to_string()))),
+                    }
+                }
+                // With `--radix 16`, `1b` is the hex number h1B rather than binary 1
+                else if let Some(literal) = suffix_literal(word).filter(|_| !implicit_base.is_some_and(|base| base == Base::Hexadecimal && is_implicit_number(word, base))) 
+                {
+                    scan.start_number(offset);
+                    scan.current_number.push_str(&literal);
+                }
+                else if let Some(base) = implicit_base.filter(|base| is_implicit_number(word, *base)) 
+                {
+                    scan.start_number(offset);
+                    scan.current_number.push(base.prefix());
+                    scan.current_number.push_str(word);
+                }
+                else if !is_identifier(word) 
+                {
+                    scan.start_number(offset);
+                    scan.current_number.push_str(word);
+                }
+                else 
+                {
+                    scan.push(offset..end, Token::Variable(word.to_string()));
+                }
+                if !scan.current_number.is_empty() 
+                {
+                    scan.number_end = end;
+                }
+            }
+            // A minus right after the base prefix is the literal's sign (`h-A`), as emitted by `Number::format`
+            '-' if scan.current_number.len() == 1 && scan.current_number.starts_with(is_base_prefix) => 
+            {
+                scan.current_number.push('-');
+                scan.number_end = offset + 1;
+            }
+            c @ ('<' | '>') if chars.peek().is_some_and(|&(_, next)| next == c) => 
+            {
+                chars.next();
+                scan.finish_number();
+                let op = if c == '<' { Operation::ShiftLeft } else { Operation::ShiftRight };
+                scan.push(offset..offset + 2, Token::Operation(op));
+            }
+            c @ ('(' | '[' | '{') => 
+            {
+                scan.finish_number();
+                open_brackets.push((c, call));
+                call = false;
+                scan.push_bracket(offset, c, Operation::OpenParen);
+            }
+            c @ (')' | ']' | '}') => 
+            {
+                scan.finish_number();
+                match open_brackets.pop() 
+                {
+                    Some((open, _)) if closing_bracket(open) != c => scan.errors.push((offset, CalcError::MismatchedBracket(open, c))),
+                    // A close with nothing open is reported by `shunting_yard`, which sees it as `)` whatever its kind
+                    _ => {}
+                }
+                scan.push_bracket(offset, c, Operation::CloseParen);
+            }
+            c @ ('+' | '-' | '*' | '/' | '%' | '<' | '>' | '&' | '^' | '|' | '?') => 
+            {
+                scan.finish_number();
+                let op = match Operation::from_char(c) 
+                {
+                    Ok(Operation::Subtract) if scan.negates => Ok(Operation::Negate),
+                    op => op,
+                };
+                match op 
+                {
+                    Ok(op) => scan.push(offset..offset + 1, Token::Operation(op)),
+                    Err(err) => scan.errors.push((offset, err)),
+                }
+            }
+            // With `accept_commas`, a comma between digits of a decimal number groups them, except between
+            // a call's arguments: `sum(d1,234)` adds two numbers
+            ',' if settings.accept_commas
+                && scan.current_number.starts_with('d')
+                && !open_brackets.last().is_some_and(|&(_, call)| call)
+                && chars.peek().is_some_and(|&(_, next)| next.is_ascii_digit()) => 
+            {
+                scan.current_number.push(',');
+                scan.number_end = offset + 1;
+            }
+            ',' => 
+            {
+                scan.finish_number();
+                scan.push(offset..offset + 1, Token::Comma);
+            }
+            ':' => 
+            {
+                scan.finish_number();
+                scan.push(offset..offset + 1, Token::Colon);
+            }
+            _ => 
+            {
+                scan.finish_number();
+                scan.errors.push((offset, CalcError::InvalidCharacter(c)));
+            }
+        }
+    }
+
+    if lenient || scan.errors.is_empty() 
+    {
+        scan.finish_number();
+    }
+
+    scan.errors
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn num(value: i64, base: Base) -> Token
+    {
+        Token::Number(Number { value, base })
+    }
+
+    #[test]
+    fn spaces_group_the_digits_of_one_number()
+    {
+        assert_eq!(tokenize("h DEAD BEEF"), Ok(vec![num(0xDEAD_BEEF, Base::Hexadecimal)]));
+        assert_eq!(tokenize("d1 000 000"), Ok(vec![num(1_000_000, Base::Decimal)]));
+        assert_eq!(tokenize("b1111 0000"), Ok(vec![num(0xF0, Base::Binary)]));
+    }
+
+    #[test]
+    fn prefix_letters_inside_a_number_are_digits()
+    {
+        let hex = |value| Ok(vec![num(value, Base::Hexadecimal)]);
+        assert_eq!(tokenize("hEF"), hex(0xEF));
+        assert_eq!(tokenize("hDB"), hex(0xDB));
+        assert_eq!(tokenize("h dead beef"), hex(0xDEAD_BEEF));
+        assert_eq!(tokenize("h dead beef"), tokenize("hDEADBEEF"));
+        assert!(is_digit_group("beef"));
+        assert!(!is_digit_group("b"));
+        assert!(!is_digit_group("and"));
+    }
+
+    #[test]
+    fn spaces_never_bridge_an_operator()
+    {
+        assert_eq!(tokenize("d5 + d3"), Ok(vec![num(5, Base::Decimal), Token::Operation(Operation::Add), num(3, Base::Decimal)]));
+        assert_eq!(tokenize("h DE AD & h FF"), Ok(vec![num(0xDEAD, Base::Hexadecimal), Token::Operation(Operation::BitAnd), num(0xFF, Base::Hexadecimal)]));
+        assert_eq!(tokenize("d1 0 and d2"), Ok(vec![num(10, Base::Decimal), Token::Operation(Operation::BitAnd), num(2, Base::Decimal)]));
+    }
+
+    #[test]
+    fn mod_style_picks_the_sign_of_the_remainder()
+    {
+        let truncated = Settings::default();
+        let floored = Settings { mod_style: ModStyle::Floored, ..Settings::default() };
+        assert_eq!(Operation::Modulo.apply(-7, 3, &truncated), Ok(-1));
+        assert_eq!(Operation::Modulo.apply(7, -3, &truncated), Ok(1));
+        assert_eq!(Operation::Modulo.apply(-7, 3, &floored), Ok(2));
+        assert_eq!(Operation::Modulo.apply(7, -3, &floored), Ok(-2));
+    }
+
+    #[test]
+    fn div_round_picks_the_direction_of_a_quotient()
+    {
+        let truncated = Settings::default();
+        let floored = Settings { div_round: DivRound::Floored, ..Settings::default() };
+        assert_eq!(Operation::Divide.apply(-7, 2, &truncated), Ok(-3));
+        assert_eq!(Operation::Divide.apply(7, -2, &truncated), Ok(-3));
+        assert_eq!(Operation::Divide.apply(-7, 2, &floored), Ok(-4));
+        assert_eq!(Operation::Divide.apply(7, -2, &floored), Ok(-4));
+        assert_eq!(Operation::Divide.apply(7, 2, &floored), Ok(3));
+    }
+
+    #[test]
+    fn negative_shift_amounts()
+    {
+        let strict = Settings::default();
+        let reverse = Settings { negative_shift: NegativeShift::Reverse, ..Settings::default() };
+        assert_eq!(Operation::ShiftLeft.apply(8, -2, &strict), Err(CalcError::InvalidShiftAmount(-2)));
+        assert_eq!(Operation::ShiftRight.apply(8, -2, &strict), Err(CalcError::InvalidShiftAmount(-2)));
+        assert_eq!(Operation::ShiftLeft.apply(8, -2, &reverse), Ok(2));
+        assert_eq!(Operation::ShiftRight.apply(8, -2, &reverse), Ok(32));
+        assert_eq!(Operation::ShiftLeft.apply(8, 2, &reverse), Ok(32));
+    }
+
+    #[test]
+    fn overflow_modes_at_the_boundary()
+    {
+        let mode = |overflow| Settings { overflow, ..Settings::default() };
+        let (checked, wrapping, saturating) = (mode(OverflowMode::Checked), mode(OverflowMode::Wrapping), mode(OverflowMode::Saturating));
+
+        assert!(matches!(Operation::Add.apply(i64::MAX, 1, &checked), Err(CalcError::Overflow(_))));
+        assert_eq!(Operation::Add.apply(i64::MAX, 1, &wrapping), Ok(i64::MIN));
+        assert_eq!(Operation::Add.apply(i64::MAX, 1, &saturating), Ok(i64::MAX));
+        assert_eq!(Operation::Add.apply(i64::MAX - 1, 1, &checked), Ok(i64::MAX));
+
+        assert!(matches!(Operation::Subtract.apply(i64::MIN, 1, &checked), Err(CalcError::Overflow(_))));
+        assert_eq!(Operation::Subtract.apply(i64::MIN, 1, &wrapping), Ok(i64::MAX));
+        assert_eq!(Operation::Subtract.apply(i64::MIN, 1, &saturating), Ok(i64::MIN));
+        assert_eq!(Operation::Subtract.apply(i64::MIN + 1, 1, &checked), Ok(i64::MIN));
+
+        assert!(matches!(Operation::Multiply.apply(i64::MAX, 2, &checked), Err(CalcError::Overflow(_))));
+        assert_eq!(Operation::Multiply.apply(i64::MAX, 2, &wrapping), Ok(-2));
+        assert_eq!(Operation::Multiply.apply(i64::MAX, 2, &saturating), Ok(i64::MAX));
+        assert_eq!(Operation::Multiply.apply(i64::MIN, 2, &saturating), Ok(i64::MIN));
+        assert_eq!(Operation::Multiply.apply(i64::MIN / 2, 2, &checked), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn suffix_literals_beside_prefix_ones()
+    {
+        assert_eq!(tokenize("FFh"), Ok(vec![num(255, Base::Hexadecimal)]));
+        assert_eq!(tokenize("1010b+377o+255d"), Ok(vec![num(10, Base::Binary), Token::Operation(Operation::Add), num(255, Base::Octal), Token::Operation(Operation::Add), num(255, Base::Decimal)]));
+        assert_eq!(tokenize("1h"), tokenize("h1"));
+        assert_eq!(tokenize("12b"), Err(CalcError::InvalidDigit(Base::Binary, '2', "12".into())));
+        // Lowercase letters make a name, not a suffix literal
+        assert_eq!(tokenize("each"), Ok(vec![Token::Variable("each".into())]));
+
+        let mut session = crate::session::Session::new();
+        let mut eval = |input| session.eval(input).map(|result| result.to_string());
+        assert_eq!(eval("FFh + 1h h"), Ok("h100".into()));
+        assert_eq!(eval("hFF + h1 h"), Ok("h100".into()));
+        assert_eq!(eval("FFh d"), Ok("d255".into()));
+        assert_eq!(eval("FFh"), Ok("hFF".into()));
+    }
+
+    #[test]
+    fn every_operator_is_listed_once()
+    {
+        // No wildcard: a new variant doesn't compile until it is sorted in here, and in `Operation::ALL`
+        let listed = |op: Operation| match op
+        {
+            Operation::Add | Operation::Subtract | Operation::Multiply | Operation::Divide | Operation::Modulo => true,
+            Operation::ShiftLeft | Operation::ShiftRight | Operation::Less | Operation::Greater => true,
+            Operation::BitAnd | Operation::BitXor | Operation::BitOr | Operation::Negate | Operation::Conditional => true,
+            Operation::OpenParen | Operation::CloseParen => false,
+        };
+        for (i, op) in Operation::ALL.iter().enumerate()
+        {
+            assert!(!Operation::ALL[..i].contains(op), "{:?} is in ALL twice", op);
+        }
+
+        let table = operators();
+        for op in Operation::ALL
+        {
+            let count = table.iter().filter(|info| info.operation == op).count();
+            assert_eq!(count, usize::from(listed(op)), "{:?}", op);
+        }
+        assert_eq!(table.len(), Operation::ALL.iter().filter(|op| listed(**op)).count());
+        assert!(table.windows(2).all(|pair| pair[0].precedence >= pair[1].precedence));
+    }
+
+    // Where a `_` may sit in a literal: only ever between two digits
+    mod separators
+    {
+        use super::*;
+
+        fn invalid(base: Base, digits: &str) -> Result<Vec<Token>, CalcError>
+        {
+            Err(CalcError::InvalidNumber(base, digits.into()))
+        }
+
+        #[test]
+        fn right_after_the_prefix()
+        {
+            assert_eq!(tokenize("d_5"), invalid(Base::Decimal, "_5"));
+            assert_eq!(tokenize("h_FF"), invalid(Base::Hexadecimal, "_FF"));
+            assert_eq!(tokenize("b_1"), invalid(Base::Binary, "_1"));
+            assert_eq!(tokenize("d_5 + d1"), invalid(Base::Decimal, "_5"));
+            let mut session = crate::session::Session::new();
+            assert_eq!(session.eval("d_5 = d1").map(|_| ()), Err(CalcError::InvalidVariableName("d_5".into())));
+            assert_eq!(session.variable("d_5"), None);
+        }
+
+        #[test]
+        fn at_the_end()
+        {
+            assert_eq!(tokenize("d5_"), invalid(Base::Decimal, "5_"));
+            assert_eq!(tokenize("hFF_"), invalid(Base::Hexadecimal, "FF_"));
+        }
+
+        #[test]
+        fn doubled()
+        {
+            assert_eq!(tokenize("d5__0"), invalid(Base::Decimal, "5__0"));
+            assert_eq!(tokenize("hF__F"), invalid(Base::Hexadecimal, "F__F"));
+        }
+
+        #[test]
+        fn between_digits()
+        {
+            assert_eq!(tokenize("d1_000"), Ok(vec![num(1000, Base::Decimal)]));
+            assert_eq!(tokenize("hFF_FF"), Ok(vec![num(0xFFFF, Base::Hexadecimal)]));
+            assert_eq!(tokenize("b1010_0101"), Ok(vec![num(0xA5, Base::Binary)]));
+            assert_eq!(tokenize("d-1_000"), Ok(vec![num(-1000, Base::Decimal)]));
+        }
+
+        #[test]
+        fn names_are_left_alone()
+        {
+            assert_eq!(tokenize("h_count"), Ok(vec![Token::Variable("h_count".into())]));
+            assert_eq!(tokenize("d_"), Ok(vec![Token::Variable("d_".into())]));
+            assert!(!is_identifier("d_5"));
+            assert!(is_identifier("_5"));
+        }
+    }
+
+    #[test]
+    fn accept_commas_groups_decimal_digits_outside_calls()
+    {
+        let commas = Settings { accept_commas: true, ..Settings::default() };
+        let tokens = |expr| tokenize_with(expr, &commas);
+        assert_eq!(tokens("d1,234,567"), Ok(vec![num(1_234_567, Base::Decimal)]));
+        assert_eq!(tokens("d-1,000"), Ok(vec![num(-1000, Base::Decimal)]));
+        assert_eq!(tokens("d12,345 + d1"), Ok(vec![num(12_345, Base::Decimal), Token::Operation(Operation::Add), num(1, Base::Decimal)]));
+        // Off, the comma separates as always
+        assert_eq!(tokenize("d1,d2"), Ok(vec![num(1, Base::Decimal), Token::Comma, num(2, Base::Decimal)]));
+
+        assert_eq!(tokens("d1,23"), Err(CalcError::InvalidNumber(Base::Decimal, "1,23".into())));
+        assert_eq!(tokens("d1234,567"), Err(CalcError::InvalidNumber(Base::Decimal, "1234,567".into())));
+        assert_eq!(tokens("d1,2345"), Err(CalcError::InvalidNumber(Base::Decimal, "1,2345".into())));
+        assert_eq!(tokens("d,123"), Err(CalcError::InvalidNumber(Base::Decimal, ",123".into())));
+        // Only decimal numbers are grouped by commas
+        assert_eq!(tokens("hFF,0"), Err(CalcError::InvalidBase('0')));
+
+        // Between a call's arguments a comma still separates, unless the number has parentheses of its own
+        let separated = vec![Token::Function(Function::Sum), Token::Operation(Operation::OpenParen), num(1, Base::Decimal), Token::Comma, num(2, Base::Decimal), Token::Operation(Operation::CloseParen)];
+        assert_eq!(tokens("sum(d1,d2)"), Ok(separated));
+        assert_eq!(tokens("sum(d1,234)"), Err(CalcError::InvalidBase('2')));
+        let mut session = crate::session::Session::new();
+        *session.settings_mut() = commas;
+        assert_eq!(session.eval("sum((d1,234,567), d1) d").map(|result| result.to_string()), Ok("d1234568".into()));
+        assert_eq!(session.eval("(d1,234) d").map(|result| result.to_string()), Ok("d1234".into()));
+    }
+
+    #[test]
+    fn disabled_operators_are_errors()
+    {
+        let settings = Settings { disabled_ops: vec![Operation::Multiply, Operation::OpenParen, Operation::CloseParen, Operation::BitAnd], ..Settings::default() };
+        let tokens = |expr| tokenize_with(expr, &settings);
+        assert_eq!(tokens("d2 * d3"), Err(CalcError::DisabledOperator("*")));
+        assert_eq!(tokens("(d1)"), Err(CalcError::DisabledOperator("(")));
+        assert_eq!(tokens("d1)"), Err(CalcError::DisabledOperator(")")));
+        // A bracket is reported as typed, though all kinds are disabled along with the parenthesis
+        assert_eq!(tokens("[d1]"), Err(CalcError::DisabledOperator("[")));
+        assert_eq!(tokens("{d1"), Err(CalcError::DisabledOperator("{")));
+        assert_eq!(tokens("d1}"), Err(CalcError::DisabledOperator("}")));
+        // The spelled-out form goes with its symbol
+        assert_eq!(tokens("d6 and d3"), Err(CalcError::DisabledOperator("&")));
+
+        assert_eq!(tokens("d2 + d3 - d1"), Ok(vec![num(2, Base::Decimal), Token::Operation(Operation::Add), num(3, Base::Decimal), Token::Operation(Operation::Subtract), num(1, Base::Decimal)]));
+        assert_eq!(tokens("d6 | d1").map(|tokens| tokens.len()), Ok(3));
+
+        // Negation is written with `-`, so it is disabled along with subtraction
+        let no_minus = Settings { disabled_ops: vec![Operation::Subtract], ..Settings::default() };
+        assert_eq!(tokenize_with("-d1", &no_minus), Err(CalcError::DisabledOperator("-")));
+        assert_eq!(tokenize_with("d-1", &no_minus), Ok(vec![num(-1, Base::Decimal)]));
+    }
+
+    #[test]
+    fn bare_hex_words_are_numbers_under_a_hex_default()
+    {
+        let hex = |expr| tokenize_with_base(expr, Some(Base::Hexadecimal));
+        let add = Token::Operation(Operation::Add);
+        assert_eq!(hex("FF + A"), Ok(vec![num(0xFF, Base::Hexadecimal), add.clone(), num(0xA, Base::Hexadecimal)]));
+        assert_eq!(hex("FF + d10"), Ok(vec![num(0xFF, Base::Hexadecimal), add.clone(), num(10, Base::Decimal)]));
+        assert_eq!(hex("d10 + A"), Ok(vec![num(10, Base::Decimal), add.clone(), num(0xA, Base::Hexadecimal)]));
+        // Lowercase letters still start prefixed literals or names
+        assert_eq!(hex("dA"), Err(CalcError::InvalidDigit(Base::Decimal, 'A', "A".into())));
+        assert_eq!(hex("ff"), Ok(vec![Token::Variable("ff".into())]));
+        assert!(!is_implicit_number("FF", Base::Decimal));
+
+        let mut session = crate::session::Session::new();
+        session.settings_mut().implicit_base = Some(Base::Hexadecimal);
+        let mut eval = |input: &str| session.eval(input).map(|result| result.to_string());
+        assert_eq!(eval("FF + A"), Ok("h109".into()));
+        assert_eq!(eval("FF + A + d10"), Ok("h113".into()));
+        assert_eq!(eval("d10 + A"), Ok("d20".into()));
+    }
+}
@@ -0,0 +1,62 @@
+use std::process::{Command, Output};
+
+// Runs the calculator the way a script would, with the console never starting.
+fn run(args: &[&str]) -> Output
+{
+    Command::new(env!("CARGO_BIN_EXE_better_dev_calc")).arg("--quiet").args(args).output().expect("the calculator runs")
+}
+
+fn text(bytes: &[u8]) -> &str
+{
+    std::str::from_utf8(bytes).expect("output is UTF-8")
+}
+
+#[test]
+fn a_result_goes_to_stdout_alone()
+{
+    let output = run(&["-e", "hFF + d1 d"]);
+    assert!(output.status.success());
+    assert_eq!(text(&output.stdout), "d256\n");
+    assert_eq!(text(&output.stderr), "");
+}
+
+#[test]
+fn an_error_goes_to_stderr_alone()
+{
+    let output = run(&["-e", "d1 / d0"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(text(&output.stdout), "");
+    assert_eq!(text(&output.stderr), "Error: Division by zero\n");
+}
+
+#[test]
+fn results_and_errors_stay_apart_across_expressions()
+{
+    let output = run(&["-e", "x = d5", "-e", "x + y", "-e", "x * d2 h"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(text(&output.stdout), "x = d5\nhA\n");
+    assert_eq!(text(&output.stderr), "Error: Undefined variable: y\n");
+}
+
+#[test]
+fn dump_tokens_prints_the_readme_sample()
+{
+    let output = run(&["--dump-tokens", "hFF + x"]);
+    assert!(output.status.success());
+    let sample = r#"[{"kind":"number","value":255,"base":"hexadecimal","text":"hFF","start":0,"end":3},{"kind":"operator","symbol":"+","unary":false,"text":"+","start":4,"end":5},{"kind":"variable","name":"x","text":"x","start":6,"end":7}]"#;
+    assert_eq!(text(&output.stdout), format!("{}\n", sample));
+    assert!(include_str!("../README.md").contains(sample));
+}
+
+#[test]
+fn radix_reads_unprefixed_numbers_in_that_base()
+{
+    let output = run(&["--radix", "16", "-e", "FF + 1", "-e", "FF + 1 d", "-e", "d10 + A d"]);
+    assert!(output.status.success());
+    assert_eq!(text(&output.stdout), "h100\nd256\nd20\n");
+
+    let output = run(&["--radix", "2", "-e", "101 + 1", "-e", "12"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(text(&output.stdout), "b110\n");
+    assert_eq!(text(&output.stderr), "Error: '2' is not a binary digit: 12\n");
+}